@@ -0,0 +1,191 @@
+//! On-disk cache of the variable queries results, persisted across runs.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use super::clickhouse::{QueryKind, Table};
+use super::executor::{Executor, Query, Response};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct CacheFlags {
+    /// File caching the results of the variable queries across runs. This also allows resolving
+    /// the cached variables offline, e.g. with `plan`.
+    #[clap(long)]
+    pub variables_cache: Option<PathBuf>,
+    /// Validity of the results in the variables cache
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    pub variables_cache_ttl: Duration,
+}
+impl CacheFlags {
+    pub fn open(&self) -> anyhow::Result<Option<VariablesCache>> {
+        self.variables_cache
+            .as_deref()
+            .map(|path| VariablesCache::open(path, self.variables_cache_ttl))
+            .transpose()
+    }
+}
+
+/// Variable query missing from the cache, without an executor to run it.
+#[derive(thiserror::Error, Debug)]
+#[error("Variable query [{0}] is not in the cache")]
+pub struct CacheMiss(pub String);
+
+/// Data source uid and SQL query
+type Key = (Option<String>, String);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    datasource: Option<String>,
+    sql: String,
+    /// Unix time of the execution, in seconds
+    time: u64,
+    table: Table,
+}
+
+/// Results of the variable queries, by data source and SQL query, stored as a JSON file.
+pub struct VariablesCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+impl VariablesCache {
+    /// Read the cache, discarding the results older than `ttl`. The file is created on save if
+    /// it does not exist.
+    pub fn open(path: &Path, ttl: Duration) -> anyhow::Result<Self> {
+        let entries: Vec<Entry> = match std::fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data)
+                .with_context(|| format!("Invalid variables cache {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("Could not read {:?}", path)),
+        };
+        let now = now();
+        let n_entries = entries.len();
+        let entries: HashMap<_, _> = entries
+            .into_iter()
+            .filter(|e| now.saturating_sub(e.time) < ttl.as_secs())
+            .map(|e| ((e.datasource.clone(), e.sql.clone()), e))
+            .collect();
+        info!(
+            n_entries,
+            n_valid = entries.len(),
+            "Read variables cache from {:?}",
+            path
+        );
+        Ok(Self {
+            path: path.into(),
+            entries: Mutex::new(entries),
+        })
+    }
+    fn get(&self, key: &Key) -> Option<Table> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).map(|e| e.table.clone())
+    }
+    fn insert(&self, key: Key, table: Table) {
+        let entry = Entry {
+            datasource: key.0.clone(),
+            sql: key.1.clone(),
+            time: now(),
+            table,
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+    /// Write the valid results to the file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = {
+            let entries = self.entries.lock().unwrap();
+            serde_json::to_vec(&entries.values().collect::<Vec<_>>())?
+        };
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Could not write variables cache {:?}", self.path))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Executor answering the variable queries (with cache enabled) from a `VariablesCache`, and
+/// delegating the other ones.
+///
+/// Without an inner executor, the missing variable queries fail with [`CacheMiss`], and the
+/// discarded queries are skipped.
+pub struct CachedExecutor<'a> {
+    inner: Option<&'a dyn Executor>,
+    cache: &'a VariablesCache,
+}
+impl<'a> CachedExecutor<'a> {
+    pub fn new(inner: Option<&'a dyn Executor>, cache: &'a VariablesCache) -> Self {
+        Self { inner, cache }
+    }
+}
+#[async_trait::async_trait]
+impl Executor for CachedExecutor<'_> {
+    async fn query_rows(&self, query: Query<'_>, cache: bool) -> anyhow::Result<Table> {
+        if !cache || query.kind != Some(QueryKind::Variable) {
+            let inner = self.inner.context("No executor to run the query")?;
+            return inner.query_rows(query, cache).await;
+        }
+        let key = (
+            query.datasource.and_then(|ds| ds.uid.clone()),
+            query.sql.to_string(),
+        );
+        if let Some(table) = self.cache.get(&key) {
+            trace!(query.sql, "Variable query cached");
+            return Ok(table);
+        }
+        let Some(inner) = self.inner else {
+            return Err(CacheMiss(query.sql.into()).into());
+        };
+        let table = inner.query_rows(query, cache).await?;
+        self.cache.insert(key, table.clone());
+        Ok(table)
+    }
+    async fn query_discard(&self, query: Query<'_>) -> anyhow::Result<Response> {
+        match self.inner {
+            Some(inner) => inner.query_discard(query).await,
+            None => Ok(Response::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ttl() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "ch-grafana-cache-variables-ttl-{}.json",
+            std::process::id()
+        ));
+        let key = |sql: &str| (Some("ch".to_string()), sql.to_string());
+        let cache = VariablesCache::open(&path, Duration::from_secs(60))?;
+        cache.insert(key("SELECT 1"), Table::default());
+        cache.insert(key("SELECT 2"), Table::default());
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&key("SELECT 2"))
+            .unwrap()
+            .time -= 120;
+        cache.save()?;
+
+        let cache = VariablesCache::open(&path, Duration::from_secs(60))?;
+        assert!(cache.get(&key("SELECT 1")).is_some());
+        assert!(cache.get(&key("SELECT 2")).is_none());
+        assert!(cache.get(&(None, "SELECT 1".into())).is_none());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
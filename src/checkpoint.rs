@@ -0,0 +1,218 @@
+//! Checkpoints of the executed queries, to resume interrupted runs.
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::*;
+
+use super::grafana::Dashboard;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct CheckpointFlags {
+    /// Record the executed queries in this file, updated every few seconds and removed once all
+    /// the combinations are executed
+    #[clap(long)]
+    pub checkpoint: Option<PathBuf>,
+    /// Skip the queries recorded in the checkpoint file, unless the dashboard changed since
+    #[clap(long, requires = "checkpoint")]
+    pub resume: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    version: u64,
+    /// Hash of the dashboard variables and queries
+    fingerprint: String,
+    /// Number of combinations completed, for information: the queries are skipped by hash, as
+    /// the combinations may come in another order, e.g. in the popular mode
+    combinations: usize,
+    /// Hashes of the executed queries
+    queries: BTreeSet<String>,
+}
+
+/// Minimum interval between the saves of the checkpoint during the execution, each rewriting the
+/// whole file
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Progress of an execution, persisted to a file.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    state: State,
+    /// Hashes of the queries executed by the previous run, as opposed to the ones executed by
+    /// this one, which are executed again if repeated
+    resumed: BTreeSet<String>,
+    saved: Option<Instant>,
+}
+impl Checkpoint {
+    /// Checkpoint for the dashboard, resuming from the file if requested and if the dashboard did
+    /// not change.
+    pub fn open(flags: &CheckpointFlags, dashboard: &Dashboard) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &flags.checkpoint else {
+            return Ok(None);
+        };
+        let state = State {
+            version: dashboard.version,
+            fingerprint: fingerprint(dashboard),
+            ..Default::default()
+        };
+        let mut checkpoint = Self {
+            path: path.clone(),
+            state,
+            resumed: Default::default(),
+            saved: None,
+        };
+        if flags.resume {
+            checkpoint.resume()?;
+        }
+        Ok(Some(checkpoint))
+    }
+    fn resume(&mut self) -> anyhow::Result<()> {
+        let previous: State = match std::fs::read_to_string(&self.path) {
+            Ok(data) => serde_json::from_str(&data)
+                .with_context(|| format!("Invalid checkpoint {:?}", self.path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No checkpoint at {:?}, starting from scratch", self.path);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read {:?}", self.path));
+            }
+        };
+        if (previous.version, &previous.fingerprint)
+            != (self.state.version, &self.state.fingerprint)
+        {
+            warn!(
+                previous_version = previous.version,
+                version = self.state.version,
+                "The dashboard changed since the checkpoint, starting from scratch"
+            );
+            return Ok(());
+        }
+        info!(
+            combinations = previous.combinations,
+            queries = previous.queries.len(),
+            "Resuming from checkpoint"
+        );
+        self.resumed = previous.queries.clone();
+        self.state = previous;
+        Ok(())
+    }
+    /// Whether the query was executed by the run resumed from the checkpoint
+    pub fn is_executed(&self, sql: &str) -> bool {
+        self.resumed.contains(&hash(sql))
+    }
+    pub fn record_query(&mut self, sql: &str) {
+        self.state.queries.insert(hash(sql));
+    }
+    /// Persist the checkpoint, after completing the given number of combinations.
+    pub fn save(&mut self, combinations: usize) -> anyhow::Result<()> {
+        self.state.combinations = combinations;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.state)?)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Could not write checkpoint {:?}", self.path))?;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+    /// Persist the checkpoint during the execution, unless it was saved less than
+    /// [`SAVE_INTERVAL`] ago.
+    pub fn save_throttled(&mut self, combinations: usize) -> anyhow::Result<()> {
+        if self.saved.is_some_and(|t| t.elapsed() < SAVE_INTERVAL) {
+            return Ok(());
+        }
+        self.save(combinations)
+    }
+    /// Number of combinations completed
+    pub fn combinations(&self) -> usize {
+        self.state.combinations
+    }
+    /// Remove the checkpoint file, once all the combinations are executed.
+    pub fn finish(self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Could not remove {:?}", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn hash(data: &str) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hash of the variables and queries of the dashboard, which determine the executed queries.
+fn fingerprint(dashboard: &Dashboard) -> String {
+    let mut hasher = Sha256::new();
+    for var in dashboard.variables() {
+        hasher.update(format!("{}\0{}\0{}\0", var.name, var.r#type, var.query));
+    }
+    for panel in dashboard.queried_panels() {
+        for sql in panel.sql() {
+            hasher.update(format!("{}\0{}\0", panel, sql));
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resume() -> anyhow::Result<()> {
+        let dashboard = |sql: &str| -> Dashboard {
+            serde_json::from_value(serde_json::json!({
+                "title": "test",
+                "version": 3,
+                "panels": [{
+                    "id": 1,
+                    "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" },
+                    "targets": [{ "refId": "A", "rawSql": sql }]
+                }]
+            }))
+            .unwrap()
+        };
+        let path = std::env::temp_dir().join(format!(
+            "ch-grafana-cache-checkpoint-{}.json",
+            std::process::id()
+        ));
+        let flags = CheckpointFlags {
+            checkpoint: Some(path.clone()),
+            resume: true,
+        };
+
+        let mut checkpoint = Checkpoint::open(&flags, &dashboard("SELECT 1"))?.unwrap();
+        assert!(!checkpoint.is_executed("SELECT 1"));
+        checkpoint.record_query("SELECT 1");
+        // Only skipped when resuming
+        assert!(!checkpoint.is_executed("SELECT 1"));
+        checkpoint.save(1)?;
+
+        let checkpoint = Checkpoint::open(&flags, &dashboard("SELECT 1"))?.unwrap();
+        assert!(checkpoint.is_executed("SELECT 1"));
+        assert_eq!(checkpoint.combinations(), 1);
+        // Without --resume
+        let checkpoint = Checkpoint::open(
+            &CheckpointFlags {
+                resume: false,
+                ..flags.clone()
+            },
+            &dashboard("SELECT 1"),
+        )?
+        .unwrap();
+        assert!(!checkpoint.is_executed("SELECT 1"));
+        // Changed dashboard
+        let checkpoint = Checkpoint::open(&flags, &dashboard("SELECT 2"))?.unwrap();
+        assert!(!checkpoint.is_executed("SELECT 1"));
+
+        checkpoint.finish()?;
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -1,35 +1,287 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
 use futures::stream::StreamExt;
 use reqwest::header::TRANSFER_ENCODING;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tracing::*;
 
-#[derive(clap::Parser)]
+use super::executor::{Executor, Query, Response};
+use super::throttle::{Throttle, ThrottleFlags};
+use super::tls::TlsFlags;
+
+#[derive(clap::Args)]
 pub struct Flags {
     /// URL to the Clickhouse HTTP endpoint
     #[clap(long, env = "CLICKHOUSE_URL")]
     pub url: reqwest::Url,
+    #[clap(flatten)]
+    pub auth: AuthFlags,
+    #[clap(flatten)]
+    pub settings: SettingsFlags,
+    #[clap(flatten)]
+    pub profile: ProfileFlags,
+    #[clap(flatten)]
+    pub tls: TlsFlags,
+    #[clap(flatten)]
+    pub throttle: ThrottleFlags,
+    /// Maximum duration of each query, e.g. `5m`, including the retries. Queries exceeding it
+    /// are cancelled on the server with `KILL QUERY`.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub query_timeout: Option<Duration>,
+}
+
+/// Authentication of the requests sent to Clickhouse.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Auth {
+    /// HTTP basic authentication, with --username and --password
+    #[default]
+    Basic,
+    /// `X-ClickHouse-User` and `X-ClickHouse-Key` headers, with --username and --password
+    Headers,
+    /// `Authorization: Bearer` header, e.g. with a JWT passed as --token
+    Bearer,
+    /// No credentials
+    None,
+}
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct AuthFlags {
+    /// Authentication method
+    #[clap(long, env = "CLICKHOUSE_AUTH", value_enum, default_value_t)]
+    pub auth: Auth,
     /// Clickhouse username
     #[clap(long, env = "CLICKHOUSE_USERNAME")]
-    pub username: String,
-    #[clap(long, env = "CLICKHOUSE_PASSWORD")]
+    pub username: Option<String>,
+    #[clap(long, env = "CLICKHOUSE_PASSWORD", hide_env_values = true)]
     pub password: Option<String>,
+    /// File containing the password, e.g. a mounted Kubernetes secret
+    #[clap(long, env = "CLICKHOUSE_PASSWORD_FILE", conflicts_with = "password")]
+    pub password_file: Option<PathBuf>,
+    /// Bearer token, for --auth bearer
+    #[clap(long, env = "CLICKHOUSE_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// File containing the bearer token, e.g. a mounted Kubernetes secret
+    #[clap(long, env = "CLICKHOUSE_TOKEN_FILE", conflicts_with = "token")]
+    pub token_file: Option<PathBuf>,
+}
+impl AuthFlags {
+    /// Add the credentials to the requests.
+    fn apply(
+        &self,
+        builder: reqwest_middleware::RequestBuilder,
+    ) -> anyhow::Result<reqwest_middleware::RequestBuilder> {
+        let username = || {
+            self.username.as_deref().with_context(|| {
+                let auth = format!("{:?}", self.auth).to_lowercase();
+                format!("--auth {} requires --username", auth)
+            })
+        };
+        let password = secret(self.password.as_deref(), self.password_file.as_deref())?;
+        Ok(match self.auth {
+            Auth::Basic => builder.basic_auth(username()?, password),
+            Auth::Headers => {
+                let mut builder = builder.header("X-ClickHouse-User", username()?);
+                if let Some(password) = password {
+                    let mut key = reqwest::header::HeaderValue::try_from(password)
+                        .context("Invalid password")?;
+                    key.set_sensitive(true);
+                    builder = builder.header("X-ClickHouse-Key", key);
+                }
+                builder
+            }
+            Auth::Bearer => {
+                let token = secret(self.token.as_deref(), self.token_file.as_deref())?
+                    .context("--auth bearer requires --token or --token-file")?;
+                builder.bearer_auth(token)
+            }
+            Auth::None => builder,
+        })
+    }
+}
+/// Secret passed directly or in a file, without the trailing newline.
+fn secret(value: Option<&str>, file: Option<&Path>) -> anyhow::Result<Option<String>> {
+    match (value, file) {
+        (Some(value), _) => Ok(Some(value.into())),
+        (None, Some(file)) => Ok(Some(
+            std::fs::read_to_string(file)
+                .with_context(|| format!("Could not read {:?}", file))?
+                .trim_end_matches(['\n', '\r'])
+                .into(),
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Shape of the HTTP requests sent to Clickhouse.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+    /// Chunked requests, with the settings after the response format
+    #[default]
+    Default,
+    /// Reproduce the requests of the Grafana Clickhouse data source (clickhouse-go over HTTP),
+    /// so that the chproxy cache keys of the panel queries match
+    GrafanaCompat,
+}
+
+/// HTTP compression configured in the Grafana data source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Deflate,
+    Br,
+}
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ProfileFlags {
+    /// Shape of the HTTP requests
+    #[clap(long, value_enum, default_value_t)]
+    pub profile: Profile,
+    /// HTTP compression configured in the Grafana data source (grafana-compat profile)
+    #[clap(long, value_enum, default_value_t)]
+    pub compression: Compression,
+    /// Default database, as configured in the Grafana data source
+    #[clap(long, env = "CLICKHOUSE_DATABASE")]
+    pub database: Option<String>,
+}
+
+/// Clickhouse settings, sent as URL parameters.
+pub type Settings = BTreeMap<String, String>;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct SettingsFlags {
+    /// Clickhouse setting sent with every query, e.g. `use_query_cache=1` (repeatable)
+    #[clap(long = "setting", value_name = "KEY=VALUE", value_parser = parse_setting)]
+    pub all: Vec<(String, String)>,
+    /// Clickhouse setting sent with the variable queries only (repeatable)
+    #[clap(long = "variables-setting", value_name = "KEY=VALUE", value_parser = parse_setting)]
+    pub variables: Vec<(String, String)>,
+    /// Clickhouse setting sent with the panel queries only (repeatable)
+    #[clap(long = "panels-setting", value_name = "KEY=VALUE", value_parser = parse_setting)]
+    pub panels: Vec<(String, String)>,
+    /// YAML file of the form { all: { setting: value }, variables: { ... }, panels: { ... } }.
+    /// The command line settings take precedence.
+    #[clap(long, value_parser = SettingsConfig::from_file)]
+    pub settings_file: Option<SettingsConfig>,
+}
+fn parse_setting(s: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .context("Settings must be of the form key=value")?;
+    Ok((key.trim().into(), value.trim().into()))
+}
+
+/// Clickhouse settings per kind of query.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsConfig {
+    #[serde(default, deserialize_with = "deserialize_settings")]
+    pub all: Settings,
+    #[serde(default, deserialize_with = "deserialize_settings")]
+    pub variables: Settings,
+    #[serde(default, deserialize_with = "deserialize_settings")]
+    pub panels: Settings,
+}
+impl SettingsConfig {
+    fn from_file(path: &str) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_str(
+            &std::fs::read_to_string(path).with_context(|| format!("Could not open {}", path))?,
+        )?)
+    }
+    fn from_flags(flags: &SettingsFlags) -> Self {
+        let mut config = flags.settings_file.clone().unwrap_or_default();
+        // The command line prevails over the file, including over its per-kind sections.
+        for (key, _) in &flags.all {
+            config.variables.remove(key);
+            config.panels.remove(key);
+        }
+        config.all.extend(flags.all.iter().cloned());
+        config.variables.extend(flags.variables.iter().cloned());
+        config.panels.extend(flags.panels.iter().cloned());
+        config
+    }
+    /// Settings for the given kind of queries
+    fn get(&self, kind: Option<QueryKind>) -> Settings {
+        let mut settings = self.all.clone();
+        match kind {
+            Some(QueryKind::Variable) => settings.extend(self.variables.clone()),
+            Some(QueryKind::Panel) => settings.extend(self.panels.clone()),
+            Some(QueryKind::Internal) => settings.clear(),
+            None => {}
+        }
+        settings
+    }
+}
+// Allow YAML scalars of any type as values.
+fn deserialize_settings<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Settings, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        Bool(bool),
+        Int(i64),
+        Float(f64),
+        String(String),
+    }
+    Ok(BTreeMap::<String, Scalar>::deserialize(d)?
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Scalar::Bool(b) => (b as u8).to_string(),
+                Scalar::Int(i) => i.to_string(),
+                Scalar::Float(f) => f.to_string(),
+                Scalar::String(s) => s,
+            };
+            (k, v)
+        })
+        .collect())
+}
+
+/// Kind of query, determining which settings are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    Variable,
+    Panel,
+    /// Query of this tool on the system tables, sent without any of the configured settings,
+    /// e.g. as Clickhouse rejects `use_query_cache` on system tables
+    Internal,
 }
 
 pub struct ChClient {
     builder: reqwest_middleware::RequestBuilder,
-    cache: Arc<tokio::sync::Mutex<HashMap<String, Vec<ResultRow>>>>,
+    /// Same, without the retries and the throttling, for `KILL QUERY`
+    control: reqwest_middleware::RequestBuilder,
+    cache: Arc<tokio::sync::Mutex<HashMap<String, Table>>>,
+    settings: Arc<SettingsConfig>,
+    kind: Option<QueryKind>,
+    profile: ProfileFlags,
+    query_timeout: Option<Duration>,
+    /// Number of executions of each query, by hash, numbering the query ids
+    executions: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    /// Random prefix of the query ids, distinguishing them from the ones of other processes
+    nonce: String,
 }
 impl Clone for ChClient {
     fn clone(&self) -> Self {
         Self {
             builder: self.builder.try_clone().unwrap(),
+            control: self.control.try_clone().unwrap(),
             cache: self.cache.clone(),
+            settings: self.settings.clone(),
+            kind: self.kind,
+            profile: self.profile.clone(),
+            query_timeout: self.query_timeout,
+            executions: self.executions.clone(),
+            nonce: self.nonce.clone(),
         }
     }
 }
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct ResultRow {
     pub cols: Vec<String>,
 }
@@ -38,26 +290,126 @@ impl ResultRow {
         self.cols.len()
     }
 }
+/// Amount of data a query is expected to read, as reported by `EXPLAIN ESTIMATE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Estimate {
+    pub parts: u64,
+    pub rows: u64,
+    pub marks: u64,
+}
+impl std::ops::AddAssign for Estimate {
+    fn add_assign(&mut self, other: Self) {
+        self.parts += other.parts;
+        self.rows += other.rows;
+        self.marks += other.marks;
+    }
+}
+/// Rows returned by a query, along with the column names.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Table {
+    pub names: Vec<String>,
+    pub rows: Vec<ResultRow>,
+}
+impl Table {
+    pub fn n_cols(&self) -> usize {
+        self.names.len()
+    }
+    /// Index of the column with the given name
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+}
 impl ChClient {
-    pub fn from_flags(flags: &Flags) -> Self {
+    pub fn from_flags(flags: &Flags) -> anyhow::Result<Self> {
         let retry_policy =
             reqwest_retry::policies::ExponentialBackoff::builder().build_with_max_retries(3);
-        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
-            .with(reqwest_retry::RetryTransientMiddleware::new_with_policy(
+        let http_client = flags.tls.client()?;
+        let mut client = reqwest_middleware::ClientBuilder::new(http_client.clone()).with(
+            reqwest_retry::RetryTransientMiddleware::new_with_policy_and_strategy(
                 retry_policy,
-            ))
-            .build();
-        ChClient {
-            builder: client
-                .post(flags.url.clone())
-                .header(TRANSFER_ENCODING, "chunked")
-                .header(
-                    reqwest::header::USER_AGENT,
-                    format!("ch-grafana-cache/{}", env!("CARGO_PKG_VERSION")),
-                )
-                .basic_auth(&flags.username, flags.password.clone()),
+                RetryStrategy,
+            ),
+        );
+        client = client.with(ReplaceOnRetry);
+        if let Some(throttle) = Throttle::from_flags(&flags.throttle) {
+            client = client.with(throttle);
+        }
+        let request = |client: reqwest_middleware::ClientWithMiddleware| {
+            let mut builder = flags.auth.apply(client.post(flags.url.clone()).header(
+                reqwest::header::USER_AGENT,
+                format!("ch-grafana-cache/{}", env!("CARGO_PKG_VERSION")),
+            ))?;
+            if flags.profile.profile == Profile::Default {
+                builder = builder.header(TRANSFER_ENCODING, "chunked");
+            }
+            anyhow::Ok(builder)
+        };
+        Ok(ChClient {
+            builder: request(client.build())?,
+            control: request(reqwest_middleware::ClientBuilder::new(http_client).build())?,
             cache: Default::default(),
+            settings: Arc::new(SettingsConfig::from_flags(&flags.settings)),
+            kind: None,
+            profile: flags.profile.clone(),
+            query_timeout: flags.query_timeout,
+            executions: Default::default(),
+            nonce: format!("{:08x}", rand::random::<u32>()),
+        })
+    }
+    /// URL parameters and `Accept-Encoding` header of a query.
+    ///
+    /// In the grafana-compat profile, this follows clickhouse-go, which sorts the parameters
+    /// and only enables compression for the Native format. Without an explicit `Accept-Encoding`,
+    /// `gzip` is sent by reqwest, like the Go HTTP client does.
+    fn request_params(&self, default_format: &str) -> (Vec<(String, String)>, Option<&str>) {
+        let mut params = vec![("default_format".to_string(), default_format.to_string())];
+        if let Some(database) = &self.profile.database {
+            params.push(("database".into(), database.clone()));
+        }
+        params.extend(self.settings.get(self.kind));
+        let mut accept_encoding = None;
+        if self.profile.profile == Profile::GrafanaCompat {
+            if default_format == "Native" && self.profile.compression != Compression::None {
+                params.push(("enable_http_compression".into(), "1".into()));
+                accept_encoding = match self.profile.compression {
+                    Compression::Deflate => Some("deflate"),
+                    Compression::Br => Some("br"),
+                    Compression::Gzip | Compression::None => None,
+                };
+            }
+            params.sort();
+        }
+        (params, accept_encoding)
+    }
+    /// Client sending the settings configured for the given kind of queries, sharing the cache.
+    pub fn for_queries(&self, kind: QueryKind) -> Self {
+        self.with_kind(Some(kind))
+    }
+    /// Discard the rows cached by the queries executed with cache enabled.
+    pub async fn clear_cache(&self) {
+        self.cache.lock().await.clear();
+    }
+    fn with_kind(&self, kind: Option<QueryKind>) -> Self {
+        Self {
+            kind,
+            ..self.clone()
+        }
+    }
+    /// Id of the next execution of a query, of the form `ch-grafana-cache-<nonce>-<hash>-<n>`,
+    /// where the nonce is drawn once per client, the hash covers the query and its settings, and
+    /// `n` numbers the executions by this client.
+    fn query_id(&self, query: &str, default_format: &str) -> String {
+        let mut hasher = Sha256::new();
+        for (key, value) in self.request_params(default_format).0 {
+            hasher.update(format!("{}={}\0", key, value));
         }
+        hasher.update(query);
+        let hash = format!("{:x}", hasher.finalize());
+        let hash = &hash[..16];
+        let mut executions = self.executions.lock().unwrap();
+        let n = executions.entry(hash.into()).or_default();
+        *n += 1;
+        format!("ch-grafana-cache-{}-{}-{}", self.nonce, hash, n)
     }
     /// Send a query and return the resulting `reqwest::Response`.
     pub async fn send_query(
@@ -65,70 +417,571 @@ impl ChClient {
         query: String,
         default_format: &str,
     ) -> anyhow::Result<reqwest::Response> {
-        debug!("Sending query");
-        let resp = self
-            .clone()
-            .builder
-            .query(&[("default_format", default_format)])
-            .body(query.clone())
-            .send()
-            .await?;
+        let query_id = self.query_id(&query, default_format);
+        self.send_query_with_id(&query_id, query, default_format)
+            .await
+    }
+    async fn send_query_with_id(
+        &self,
+        query_id: &str,
+        query: String,
+        default_format: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        debug!(query_id, "Sending query");
+        let (mut params, accept_encoding) = self.request_params(default_format);
+        // Grafana does not send any, and the parameters are part of the chproxy cache key.
+        if self.profile.profile != Profile::GrafanaCompat {
+            params.push(("query_id".into(), query_id.into()));
+        }
+        let mut builder = self.clone().builder.query(&params);
+        if let Some(accept_encoding) = accept_encoding {
+            builder = builder.header(reqwest::header::ACCEPT_ENCODING, accept_encoding);
+        }
+        let resp = builder.body(query.clone()).send().await?;
         debug!("{:?}", resp.headers());
-        if !resp.status().is_success() {
-            anyhow::bail!(
-                "{}: {}",
-                resp.status(),
-                resp.text().await.unwrap_or_default()
-            );
+        error_for_status(resp).await
+    }
+    /// Run a query with the given id, killing it on the server if it exceeds the timeout.
+    async fn with_timeout<T>(
+        &self,
+        query_id: &str,
+        run: impl std::future::Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let Some(timeout) = self.query_timeout else {
+            return run.await;
+        };
+        let Ok(result) = tokio::time::timeout(timeout, run).await else {
+            if self.profile.profile == Profile::GrafanaCompat {
+                // The id was not sent, the query is left to its max_execution_time.
+                warn!(?timeout, "Query timed out, abandoning it");
+            } else {
+                warn!(query_id, ?timeout, "Query timed out, killing it");
+                if let Err(e) = self.kill_query(query_id).await {
+                    warn!(query_id, "Failed to kill query: {:#}", e);
+                }
+            }
+            return Err(Error::QueryTimeout {
+                query_id: query_id.into(),
+                timeout,
+            }
+            .into());
+        };
+        result
+    }
+    /// Cancel a running query, once and without waiting for the throttle.
+    ///
+    /// The query is only killed on the server receiving the request: behind a load balancer, it
+    /// may not be the one running the query, as `ON CLUSTER` is not used.
+    async fn kill_query(&self, query_id: &str) -> anyhow::Result<()> {
+        let query = format!(
+            "KILL QUERY WHERE query_id = '{}'",
+            query_id.replace('\\', "\\\\").replace('\'', "\\'")
+        );
+        let mut builder = self.control.try_clone().unwrap();
+        if let Some(timeout) = self.query_timeout {
+            builder = builder.timeout(timeout);
         }
-        Ok(resp)
+        let resp = builder
+            .query(&[("default_format", "TabSeparated")])
+            .body(query)
+            .send()
+            .await?;
+        error_for_status(resp).await?.text().await?;
+        Ok(())
     }
     /// Execute a query with Native response format, and return the total number of bytes
     #[instrument(skip(self))]
     pub async fn query_native(&self, query: String) -> anyhow::Result<usize> {
-        let resp = self.send_query(query, "Native").await?;
-        debug!("{:?}", resp.headers());
-        let mut q = resp.bytes_stream();
-        // NOTE: Not clear if we need to consume for the cache to succeed.
-        // We could also use https://clickhouse.com/docs/en/interfaces/http#response-buffering
-        let mut bytes = 0;
-        while let Some(q) = q.next().await {
-            bytes += q?.len();
-        }
+        Ok(self.query_native_response(query).await?.bytes)
+    }
+    /// Execute a query with Native response format, and return the total number of bytes along
+    /// with the cache status and query id.
+    #[instrument(skip(self))]
+    pub async fn query_native_response(&self, query: String) -> anyhow::Result<Response> {
+        let query_id = self.query_id(&query, "Native");
+        self.with_timeout(&query_id, async {
+            let resp = self.send_query_with_id(&query_id, query, "Native").await?;
+            let header = |name| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|h| h.to_str().ok())
+                    .map(String::from)
+            };
+            let cache_hit = header("x-cache").map(|c| c == "HIT");
+            let query_id = header("x-clickhouse-query-id");
+            let mut q = resp.bytes_stream();
+            // NOTE: Not clear if we need to consume for the cache to succeed.
+            // We could also use https://clickhouse.com/docs/en/interfaces/http#response-buffering
+            let mut bytes = 0;
+            while let Some(q) = q.next().await {
+                bytes += q?.len();
+            }
 
-        Ok(bytes)
+            Ok(Response {
+                bytes,
+                cache_hit,
+                query_id,
+            })
+        })
+        .await
     }
     /// Execute a query (with cache enabled or not) and return the resulting rows as strings
-    #[instrument(skip(self))]
     pub async fn query(&self, query: String, cache: bool) -> anyhow::Result<Vec<ResultRow>> {
+        Ok(self.query_table(query, cache).await?.rows)
+    }
+    /// Execute a query (with cache enabled or not) and return the resulting rows as strings,
+    /// along with the column names.
+    #[instrument(skip(self))]
+    pub async fn query_table(&self, query: String, cache: bool) -> anyhow::Result<Table> {
         if cache {
             let cache = self.cache.lock().await;
             if let Some(resp) = cache.get(&query) {
                 return Ok(resp.clone());
             }
         }
-        debug!("Sending query");
-        let resp = self.send_query(query.clone(), "TSV").await?;
-        let hit = resp.headers().get("x-cache").map_or(false, |c| c == "HIT");
-        trace!(hit, "Received response");
-        let rows: Vec<ResultRow> = resp
-            .text()
-            .await?
-            .lines()
-            .map(|l| ResultRow {
-                cols: l.split('\t').map(String::from).collect(),
+        let query_id = self.query_id(&query, "TSVWithNames");
+        let text = self
+            .with_timeout(&query_id, async {
+                let resp = self
+                    .send_query_with_id(&query_id, query.clone(), "TSVWithNames")
+                    .await?;
+                let hit = resp.headers().get("x-cache").is_some_and(|c| c == "HIT");
+                trace!(hit, "Received response");
+                Ok(resp.text().await?)
             })
-            .collect();
-        if !rows.is_empty() {
-            anyhow::ensure!(
-                rows.iter().all(|r| r.n_cols() == rows[0].n_cols()),
-                "Inconsistent column sizes"
-            );
-        }
+            .await?;
+        let mut lines = text.lines().map(|l| ResultRow {
+            cols: l.split('\t').map(unescape_tsv).collect(),
+        });
+        let names = lines.next().map(|r| r.cols).unwrap_or_default();
+        let rows: Vec<ResultRow> = lines.collect();
+        anyhow::ensure!(
+            rows.iter().all(|r| r.n_cols() == names.len()),
+            "Inconsistent column sizes"
+        );
+        let table = Table { names, rows };
         if cache {
             let mut cache = self.cache.lock().await;
-            cache.insert(query.clone(), rows.clone());
+            cache.insert(query.clone(), table.clone());
+        }
+        Ok(table)
+    }
+}
+
+#[async_trait::async_trait]
+impl Executor for ChClient {
+    async fn query_rows(&self, query: Query<'_>, cache: bool) -> anyhow::Result<Table> {
+        self.with_kind(query.kind)
+            .query_table(query.sql.into(), cache)
+            .await
+    }
+    async fn query_discard(&self, query: Query<'_>) -> anyhow::Result<Response> {
+        self.with_kind(query.kind)
+            .query_native_response(query.sql.into())
+            .await
+    }
+}
+
+/// Fail with the Clickhouse exception of unsuccessful responses.
+async fn error_for_status(resp: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    if !resp.status().is_success() {
+        let (status, code) = (resp.status(), exception_code(&resp));
+        let body = resp.text().await.unwrap_or_default();
+        return Err(Error::new(status, code, &body).into());
+    }
+    Ok(resp)
+}
+
+/// Clickhouse error codes signaling an overloaded server.
+///
+/// See <https://github.com/ClickHouse/ClickHouse/blob/master/src/Common/ErrorCodes.cpp>
+const OVERLOAD_ERRORS: [u32; 3] = [
+    202, // TOO_MANY_SIMULTANEOUS_QUERIES
+    203, // NO_FREE_CONNECTION
+    241, // MEMORY_LIMIT_EXCEEDED
+];
+const TIMEOUT_ERRORS: [u32; 2] = [
+    TIMEOUT_EXCEEDED,
+    209, // SOCKET_TIMEOUT
+];
+/// Query exceeding its `max_execution_time`, which would most likely time out again
+const TIMEOUT_EXCEEDED: u32 = 159;
+/// Clickhouse error codes of network failures, e.g. between the replicas
+const NETWORK_ERRORS: [u32; 2] = [
+    210, // NETWORK_ERROR
+    279, // ALL_CONNECTION_TRIES_FAILED
+];
+
+lazy_static::lazy_static! {
+    /// Error code and name in an exception message, e.g.
+    /// `Code: 62. DB::Exception: Syntax error: ... (SYNTAX_ERROR) (version 24.3.1.1)`
+    static ref EXCEPTION_CODE_RE: regex::Regex = regex::Regex::new(r"^Code: (\d+)\.").unwrap();
+    static ref EXCEPTION_NAME_RE: regex::Regex =
+        regex::Regex::new(r"\(([A-Z][A-Z0-9_]*)\)(?: \(version [^)]*\))?$").unwrap();
+}
+
+/// Exception raised by Clickhouse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exception {
+    pub code: u32,
+    /// Name of the error code, e.g. `SYNTAX_ERROR`, when present in the message
+    pub name: Option<String>,
+    pub message: String,
+}
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Error response from Clickhouse, classified by error code.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The server is overloaded, e.g. `TOO_MANY_SIMULTANEOUS_QUERIES` or `MEMORY_LIMIT_EXCEEDED`
+    #[error("Clickhouse is overloaded: {0}")]
+    Overloaded(Exception),
+    #[error("Clickhouse timeout: {0}")]
+    Timeout(Exception),
+    /// Network failure within the cluster
+    #[error("Clickhouse network error: {0}")]
+    Network(Exception),
+    /// Error in the query itself, e.g. a syntax error or a missing table
+    #[error("Clickhouse query error: {0}")]
+    Query(Exception),
+    /// Query cancelled after exceeding --query-timeout
+    #[error("Query {query_id} timed out after {timeout:?}")]
+    QueryTimeout { query_id: String, timeout: Duration },
+    /// Error without a Clickhouse error code, e.g. from a proxy
+    #[error("{status}: {body}")]
+    Http {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+}
+impl Error {
+    /// Error from the status, `X-ClickHouse-Exception-Code` header and body of a response. The
+    /// code is parsed from the body when the header is missing.
+    pub fn new(status: reqwest::StatusCode, code: Option<u32>, body: &str) -> Self {
+        let body = body.trim();
+        let Some(code) = code.or_else(|| {
+            EXCEPTION_CODE_RE
+                .captures(body)?
+                .get(1)?
+                .as_str()
+                .parse()
+                .ok()
+        }) else {
+            return Self::Http {
+                status,
+                body: body.into(),
+            };
+        };
+        Self::from_exception(Exception {
+            code,
+            name: EXCEPTION_NAME_RE
+                .captures(body)
+                .map(|c| c.get(1).unwrap().as_str().into()),
+            message: body.into(),
+        })
+    }
+    fn from_exception(exception: Exception) -> Self {
+        let code = exception.code;
+        if OVERLOAD_ERRORS.contains(&code) {
+            Self::Overloaded(exception)
+        } else if TIMEOUT_ERRORS.contains(&code) {
+            Self::Timeout(exception)
+        } else if NETWORK_ERRORS.contains(&code) {
+            Self::Network(exception)
+        } else {
+            Self::Query(exception)
+        }
+    }
+    /// Clickhouse error code
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            Self::Overloaded(e) | Self::Timeout(e) | Self::Network(e) | Self::Query(e) => {
+                Some(e.code)
+            }
+            Self::QueryTimeout { .. } | Self::Http { .. } => None,
+        }
+    }
+    /// Whether the query might succeed later, as opposed to e.g. a syntax error
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Overloaded(_) | Self::Timeout(_) | Self::Network(_) => true,
+            Self::QueryTimeout { .. } => true,
+            Self::Query(_) => false,
+            Self::Http { status, .. } => {
+                status.is_server_error()
+                    || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
         }
-        Ok(rows)
+    }
+    /// Whether the error is transient, for an error returned by [`ChClient`] or by a Clickhouse
+    /// data source through Grafana
+    pub fn is_transient_error(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<Self>().is_some_and(Self::is_transient)
+    }
+}
+
+/// Error code of a response, from the `X-ClickHouse-Exception-Code` header
+fn exception_code(resp: &reqwest::Response) -> Option<u32> {
+    resp.headers()
+        .get("x-clickhouse-exception-code")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Whether the response is an error signaling an overloaded server.
+pub fn is_overloaded(resp: &reqwest::Response) -> bool {
+    resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || exception_code(resp).is_some_and(|c| OVERLOAD_ERRORS.contains(&c))
+}
+
+/// Middleware adding `replace_running_query=1` to the retries, registered after the retry
+/// middleware: the retries reuse the query id, while the previous attempt may still be running
+/// after a connection drop, which would fail with `QUERY_WITH_SAME_ID_IS_ALREADY_RUNNING`. The
+/// first attempt is sent without it, as read-only users cannot change settings, and so are the
+/// requests without a query id.
+struct ReplaceOnRetry;
+/// Marker of a request sent at least once, kept in its extensions across the retries
+#[derive(Clone)]
+struct Attempted;
+#[async_trait::async_trait]
+impl reqwest_middleware::Middleware for ReplaceOnRetry {
+    async fn handle(
+        &self,
+        mut req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: reqwest_middleware::Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let has_id = req.url().query_pairs().any(|(key, _)| key == "query_id");
+        if extensions.insert(Attempted).is_some() && has_id {
+            req.url_mut()
+                .query_pairs_mut()
+                .append_pair("replace_running_query", "1");
+        }
+        next.run(req, extensions).await
+    }
+}
+
+/// Retry decisions based on the Clickhouse error code when available, rather than only on the
+/// HTTP status: Clickhouse answers most errors, including e.g. syntax errors, with a 500 status.
+struct RetryStrategy;
+impl reqwest_retry::RetryableStrategy for RetryStrategy {
+    fn handle(
+        &self,
+        res: &reqwest_middleware::Result<reqwest::Response>,
+    ) -> Option<reqwest_retry::Retryable> {
+        match res {
+            Ok(resp) if resp.status().is_success() => None,
+            // The body is not available at this point, only the status and error code.
+            Ok(resp) => match Error::new(resp.status(), exception_code(resp), "") {
+                Error::Http { .. } => reqwest_retry::default_on_request_success(resp),
+                // Left to --keep-going, which defers the query once rather than re-running it
+                // right away for each retry.
+                Error::Timeout(e) if e.code == TIMEOUT_EXCEEDED => {
+                    Some(reqwest_retry::Retryable::Fatal)
+                }
+                e if e.is_transient() => {
+                    debug!(code = e.code(), "Retrying after Clickhouse error");
+                    Some(reqwest_retry::Retryable::Transient)
+                }
+                _ => Some(reqwest_retry::Retryable::Fatal),
+            },
+            Err(e) => reqwest_retry::default_on_request_failure(e),
+        }
+    }
+}
+
+/// Estimate the amount of data read by a query with `EXPLAIN ESTIMATE`, summed over the tables.
+///
+/// Estimates are not cached, as the cache of the executor is meant for the variable queries and
+/// is never evicted.
+#[instrument(skip(executor, query), fields(sql = query.sql))]
+pub async fn estimate<E: Executor + ?Sized>(
+    executor: &E,
+    query: Query<'_>,
+) -> anyhow::Result<Estimate> {
+    let sql = format!("EXPLAIN ESTIMATE {}", query.sql);
+    let table = executor.query_rows(query.with_sql(&sql), false).await?;
+    let column = |name| {
+        table
+            .column(name)
+            .with_context(|| format!("Missing column {} in EXPLAIN ESTIMATE", name))
+    };
+    let (parts, rows, marks) = (column("parts")?, column("rows")?, column("marks")?);
+    let mut estimate = Estimate::default();
+    for row in &table.rows {
+        estimate += Estimate {
+            parts: row.cols[parts].parse()?,
+            rows: row.cols[rows].parse()?,
+            marks: row.cols[marks].parse()?,
+        };
+    }
+    Ok(estimate)
+}
+
+/// Unescape a field in the TSV format.
+///
+/// See <https://clickhouse.com/docs/en/interfaces/formats#tabseparated-data-formatting>
+fn unescape_tsv(field: &str) -> String {
+    if !field.contains('\\') {
+        return field.into();
+    }
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('0') => out.push('\0'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn settings() -> anyhow::Result<()> {
+        let mut config: super::SettingsConfig = serde_yaml::from_str(
+            "{ all: { use_query_cache: 1, query_cache_ttl: 300 }, panels: { use_query_cache: false } }",
+        )?;
+        config
+            .variables
+            .insert("max_execution_time".into(), "10".into());
+        let get = |kind| config.get(kind).into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            get(Some(super::QueryKind::Panel)),
+            vec![
+                ("query_cache_ttl".into(), "300".into()),
+                ("use_query_cache".into(), "0".into())
+            ]
+        );
+        assert_eq!(get(Some(super::QueryKind::Variable)).len(), 3);
+        assert_eq!(get(None).len(), 2);
+        assert!(get(Some(super::QueryKind::Internal)).is_empty());
+
+        // The command line settings override the file ones, even the per-kind ones
+        let config = super::SettingsConfig::from_flags(&super::SettingsFlags {
+            all: vec![("use_query_cache".into(), "1".into())],
+            variables: vec![("query_cache_ttl".into(), "60".into())],
+            settings_file: Some(config),
+            ..Default::default()
+        });
+        let get = |kind| config.get(Some(kind)).into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            get(super::QueryKind::Panel),
+            vec![
+                ("query_cache_ttl".into(), "300".into()),
+                ("use_query_cache".into(), "1".into())
+            ]
+        );
+        assert_eq!(
+            get(super::QueryKind::Variable),
+            vec![
+                ("max_execution_time".into(), "10".into()),
+                ("query_cache_ttl".into(), "60".into()),
+                ("use_query_cache".into(), "1".into())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn request_params() -> anyhow::Result<()> {
+        let mut flags = super::Flags {
+            url: "http://localhost:8123".parse()?,
+            auth: super::AuthFlags {
+                username: Some("default".into()),
+                ..Default::default()
+            },
+            settings: super::SettingsFlags {
+                all: vec![("use_query_cache".into(), "1".into())],
+                ..Default::default()
+            },
+            tls: Default::default(),
+            profile: super::ProfileFlags {
+                profile: super::Profile::GrafanaCompat,
+                compression: super::Compression::Br,
+                database: Some("db".into()),
+            },
+            throttle: Default::default(),
+            query_timeout: None,
+        };
+        let client = super::ChClient::from_flags(&flags)?;
+        let (params, accept_encoding) = client.request_params("Native");
+        assert_eq!(
+            params.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            vec![
+                "database",
+                "default_format",
+                "enable_http_compression",
+                "use_query_cache"
+            ]
+        );
+        assert_eq!(accept_encoding, Some("br"));
+        assert_eq!(client.request_params("TSVWithNames").0.len(), 3);
+
+        flags.profile.profile = super::Profile::Default;
+        let client = super::ChClient::from_flags(&flags)?;
+        let (params, accept_encoding) = client.request_params("Native");
+        assert_eq!(params[0], ("default_format".into(), "Native".into()));
+        assert_eq!(params.len(), 3);
+        assert!(accept_encoding.is_none());
+        Ok(())
+    }
+    #[test]
+    fn error() {
+        use super::{Error, Exception};
+        let status = reqwest::StatusCode::INTERNAL_SERVER_ERROR;
+        let body = "Code: 62. DB::Exception: Syntax error: failed at position 8 (FROM): FROM t. \
+                    (SYNTAX_ERROR) (version 24.3.1.1)\n";
+        let e = Error::new(status, None, body);
+        assert_eq!(
+            e,
+            Error::Query(Exception {
+                code: 62,
+                name: Some("SYNTAX_ERROR".into()),
+                message: body.trim().into()
+            })
+        );
+        assert!(!e.is_transient());
+
+        let e = Error::new(
+            status,
+            Some(241),
+            "Code: 241. DB::Exception: Memory limit exceeded",
+        );
+        assert!(matches!(&e, Error::Overloaded(e) if e.name.is_none()));
+        assert!(e.is_transient());
+        assert!(Error::new(status, Some(159), "").is_transient());
+
+        let e = Error::new(reqwest::StatusCode::BAD_GATEWAY, None, "Bad gateway");
+        assert!(matches!(e, Error::Http { .. }));
+        assert!(e.is_transient());
+        let e = anyhow::Error::from(e).context("Failed to run query");
+        assert!(Error::is_transient_error(&e));
+    }
+    #[test]
+    fn unescape_tsv() {
+        assert_eq!(super::unescape_tsv("abc"), "abc");
+        assert_eq!(
+            super::unescape_tsv(r"SELECT 1\nFROM t WHERE a = \'x\\y\'"),
+            "SELECT 1\nFROM t WHERE a = 'x\\y'"
+        );
     }
 }
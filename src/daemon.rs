@@ -0,0 +1,138 @@
+//! Scheduling of the warmups when running as a daemon.
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use tracing::*;
+
+/// When to run the warmups.
+#[derive(clap::Args, Clone, Debug)]
+pub struct ScheduleFlags {
+    /// Interval between the warmups, e.g. `5m`, typically the cache TTL. The runs are aligned on
+    /// multiples of the interval since the Unix epoch, e.g. at :00, :05, :10... for `5m`.
+    #[clap(long, value_parser = humantime::parse_duration,
+           required_unless_present = "cron", conflicts_with = "cron")]
+    pub interval: Option<Duration>,
+    /// Cron expression (in UTC) for the warmups, e.g. `*/5 * * * *`. A leading seconds field is
+    /// optional.
+    #[clap(long, value_parser = parse_cron)]
+    pub cron: Option<cron::Schedule>,
+    /// Discard the cached variables values after this duration, e.g. `1h`. By default, they are
+    /// discarded before each scheduled run, and only reused by the --watch runs in between.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub variables_ttl: Option<Duration>,
+}
+impl ScheduleFlags {
+    pub fn schedule(&self) -> anyhow::Result<Schedule> {
+        match (&self.cron, self.interval) {
+            (Some(cron), _) => Ok(Schedule::Cron(Box::new(cron.clone()))),
+            (None, Some(interval)) => {
+                anyhow::ensure!(
+                    interval.as_millis() > 0,
+                    "The interval must be at least 1ms"
+                );
+                Ok(Schedule::Interval(interval))
+            }
+            (None, None) => anyhow::bail!("Use --interval or --cron"),
+        }
+    }
+}
+
+/// Parse a cron expression, with or without a seconds field.
+fn parse_cron(expr: &str) -> anyhow::Result<cron::Schedule> {
+    let expr = expr.trim();
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    expr.parse()
+        .with_context(|| format!("Invalid cron expression '{}'", expr))
+}
+
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+impl Schedule {
+    /// First run strictly after the given time
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => {
+                let interval = interval.as_millis() as i64;
+                let next = (time.timestamp_millis().div_euclid(interval) + 1) * interval;
+                DateTime::from_timestamp_millis(next)
+            }
+            Schedule::Cron(schedule) => schedule.after(&time).next(),
+        }
+    }
+    /// Next run after a cycle started at `cycle_start`.
+    ///
+    /// The runs scheduled while the cycle was executing are skipped, so that cycles never
+    /// overlap.
+    pub fn next_run(&self, cycle_start: DateTime<Utc>) -> anyhow::Result<DateTime<Utc>> {
+        let now = Utc::now();
+        let next = self
+            .next_after(now)
+            .context("The schedule has no upcoming run")?;
+        if self.next_after(cycle_start).is_some_and(|t| t <= now) {
+            warn!(%next, "The warmup lasted past its next scheduled run, which was skipped");
+        }
+        Ok(next)
+    }
+}
+
+/// Sleep until the given time, if it is in the future.
+pub async fn sleep_until(time: DateTime<Utc>) {
+    tokio::time::sleep((time - Utc::now()).to_std().unwrap_or_default()).await;
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+pub async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_after() -> anyhow::Result<()> {
+        let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        let schedule = Schedule::Interval(Duration::from_secs(300));
+        assert_eq!(
+            schedule.next_after(time("2024-06-01T10:03:12Z")),
+            Some(time("2024-06-01T10:05:00Z"))
+        );
+        assert_eq!(
+            schedule.next_after(time("2024-06-01T10:05:00Z")),
+            Some(time("2024-06-01T10:10:00Z"))
+        );
+
+        let schedule = Schedule::Cron(Box::new(parse_cron("30 */2 * * *")?));
+        assert_eq!(
+            schedule.next_after(time("2024-06-01T10:31:00Z")),
+            Some(time("2024-06-01T12:30:00Z"))
+        );
+        let schedule = Schedule::Cron(Box::new(parse_cron("15 0 * * * *")?));
+        assert_eq!(
+            schedule.next_after(time("2024-06-01T10:31:00Z")),
+            Some(time("2024-06-01T11:00:15Z"))
+        );
+        assert!(parse_cron("* * *").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! Execution of SQL queries, independently of the transport.
+use super::clickhouse::{QueryKind, Table};
+use super::grafana::{Dashboard, DataSource, Panel, Target, TimeRange};
+use super::variables::VariablesAssignment;
+
+/// Query to execute, along with the dashboard context it originates from.
+///
+/// The context is ignored when sending the query directly to Clickhouse, but is required to
+/// execute it through a Grafana data source.
+#[derive(Clone, Copy, Debug)]
+pub struct Query<'a> {
+    pub sql: &'a str,
+    /// Kind of query, selecting the settings to send
+    pub kind: Option<QueryKind>,
+    pub datasource: Option<&'a DataSource>,
+    /// Panel target whose model is sent to Grafana
+    pub target: Option<&'a Target>,
+    pub time: Option<&'a TimeRange>,
+    /// Variables the query was substituted with, e.g. for a data source variable
+    pub variables: Option<&'a VariablesAssignment<'a>>,
+}
+impl<'a> Query<'a> {
+    /// Query without context
+    pub fn new(sql: &'a str) -> Self {
+        Self {
+            sql,
+            kind: None,
+            datasource: None,
+            target: None,
+            time: None,
+            variables: None,
+        }
+    }
+    /// Query of this tool itself, sent without the configured settings
+    pub fn internal(sql: &'a str) -> Self {
+        Self {
+            kind: Some(QueryKind::Internal),
+            ..Self::new(sql)
+        }
+    }
+    /// Variable query, on the data source of the variable
+    pub fn variable(
+        sql: &'a str,
+        datasource: Option<&'a DataSource>,
+        variables: &'a VariablesAssignment<'a>,
+    ) -> Self {
+        Self {
+            kind: Some(QueryKind::Variable),
+            datasource,
+            variables: Some(variables),
+            ..Self::new(sql)
+        }
+    }
+    /// Panel query, substituted from the given target
+    pub fn panel(
+        sql: &'a str,
+        dashboard: &'a Dashboard,
+        panel: &'a Panel,
+        target: &'a Target,
+        variables: &'a VariablesAssignment<'a>,
+    ) -> Self {
+        Self {
+            sql,
+            kind: Some(QueryKind::Panel),
+            datasource: panel.target_datasource(target),
+            target: Some(target),
+            time: Some(&dashboard.time),
+            variables: Some(variables),
+        }
+    }
+    /// Same context, with another SQL query
+    pub fn with_sql(self, sql: &'a str) -> Self {
+        Self { sql, ..self }
+    }
+}
+
+/// Response to a query whose payload was discarded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    pub bytes: usize,
+    /// Whether the response was served from a cache, from the `X-Cache` header
+    pub cache_hit: Option<bool>,
+    pub query_id: Option<String>,
+}
+
+/// Executor of SQL queries, e.g. directly on Clickhouse ([`crate::clickhouse::ChClient`]) or
+/// through Grafana ([`crate::grafana::GrafanaClient`]).
+#[async_trait::async_trait]
+pub trait Executor: Send + Sync {
+    /// Execute a query (with cache enabled or not) and return the resulting rows as strings,
+    /// along with the column names.
+    async fn query_rows(&self, query: Query<'_>, cache: bool) -> anyhow::Result<Table>;
+    /// Execute a query, consuming and discarding the response, e.g. to populate a cache.
+    async fn query_discard(&self, query: Query<'_>) -> anyhow::Result<Response>;
+}
@@ -1,10 +1,17 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use anyhow::Context;
+use futures::stream::{BoxStream, StreamExt};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
-use super::clickhouse::ChClient;
-use super::variables::VariablesAssignment;
+use super::cache::CacheMiss;
+use super::clickhouse::{self, ResultRow, Table};
+use super::executor::{Executor, Query, Response};
+use super::tls::TlsFlags;
+use super::variables::{VariableValue, VariablesAssignment};
 use crate::variables;
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -27,107 +34,520 @@ impl VariablesConfig {
     }
 }
 
+/// Variables values to execute the queries with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Saved selection of each variable, without retrieving the other values
+    Current,
+    /// Selection when opening the dashboard: the saved one if still available, else the first
+    /// value
+    Defaults,
+    /// All combinations of values
+    #[default]
+    All,
+    /// First values of each variable (see --top-n)
+    TopN,
+    /// Combinations observed in `system.query_log`, by decreasing frequency (see --lookback and
+    /// --top-n)
+    Popular,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct Selection {
+    /// Variables values to execute the queries with
+    #[clap(long, value_enum, default_value_t)]
+    pub mode: Mode,
+    /// Number of values per variable in the `top-n` mode (default 1), or number of combinations
+    /// in the `popular` mode (default: all observed)
+    #[clap(long)]
+    pub top_n: Option<usize>,
+    /// Period of the query log to consider in the `popular` mode
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "7d")]
+    pub lookback: std::time::Duration,
+}
+impl Selection {
+    /// Selection in the given mode, with the defaults of the command line.
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            top_n: None,
+            lookback: std::time::Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DashboardResponse {
     pub dashboard: Dashboard,
 }
 // See https://grafana.com/docs/grafana/latest/dashboards/build-dashboards/view-dashboard-json-model/
 // TODO: Support all the fields.
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "DashboardRepr")]
 pub struct Dashboard {
     pub title: String,
+    /// Incremented by Grafana on every save
+    pub version: u64,
+    /// Panels, including the ones nested in collapsed rows, excluding the rows themselves
     pub panels: Vec<Panel>,
+    /// Enabled Clickhouse annotations, represented as panels with a single target
+    pub annotations: Vec<Panel>,
     templating: TemplateList,
+    pub time: TimeRange,
+}
+#[derive(Deserialize)]
+struct DashboardRepr {
+    title: String,
+    #[serde(default)]
+    version: u64,
+    #[serde(default)]
+    panels: Vec<Panel>,
+    /// Rows of dashboards before schema version 16
+    #[serde(default)]
+    rows: Vec<LegacyRow>,
+    #[serde(default)]
+    annotations: AnnotationList,
+    #[serde(default)]
+    templating: TemplateList,
+    #[serde(default)]
+    time: TimeRange,
+}
+#[derive(Deserialize)]
+struct LegacyRow {
+    #[serde(default)]
+    panels: Vec<Panel>,
+}
+impl From<DashboardRepr> for Dashboard {
+    fn from(repr: DashboardRepr) -> Self {
+        let panels = repr
+            .panels
+            .into_iter()
+            .chain(repr.rows.into_iter().flat_map(|r| r.panels))
+            .flat_map(|mut panel| {
+                // Collapsed rows contain their panels
+                let nested = std::mem::take(&mut panel.panels);
+                std::iter::once(panel).chain(nested)
+            })
+            .filter(|panel| panel.r#type != "row")
+            .collect();
+        let annotations = repr
+            .annotations
+            .list
+            .into_iter()
+            .enumerate()
+            .filter(|(_, a)| a.enable && a.is_clickhouse_ds())
+            .map(|(i, a)| a.into_panel(i as u64))
+            .collect();
+        Self {
+            title: repr.title,
+            version: repr.version,
+            panels,
+            annotations,
+            templating: repr.templating,
+            time: repr.time,
+        }
+    }
 }
 impl Dashboard {
+    /// Panels and annotations with queries
+    pub fn queried_panels(&self) -> impl Iterator<Item = &Panel> {
+        self.panels.iter().chain(&self.annotations)
+    }
+    /// Uids of the library panels referenced by the dashboard
+    pub fn library_panel_uids(&self) -> impl Iterator<Item = &str> {
+        self.panels
+            .iter()
+            .filter_map(|p| p.library_panel.as_ref())
+            .map(|l| l.uid.as_str())
+    }
+    /// Replace the library panel references by the model of the library panels.
+    ///
+    /// The references missing from `library` are left as-is, without queries.
+    pub fn resolve_library_panels(&mut self, library: &LibraryPanels) {
+        for panel in &mut self.panels {
+            let Some(reference) = &panel.library_panel else {
+                continue;
+            };
+            let Some(element) = library.0.get(&reference.uid) else {
+                warn!(
+                    uid = reference.uid,
+                    name = reference.name,
+                    "Library panel not found, ignoring {}",
+                    panel
+                );
+                continue;
+            };
+            debug!(uid = reference.uid, "Resolving library panel of {}", panel);
+            let model = element.model.clone();
+            if !model.title.is_empty() {
+                panel.title = model.title;
+            }
+            panel.targets = model.targets;
+            panel.r#type = model.r#type;
+            panel.datasource = model.datasource;
+        }
+    }
+    /// Copy of the dashboard restricted to the panels and annotations whose queries are new or
+    /// modified compared to a previous version, or use variables that are.
+    pub fn changes(&self, previous: &Dashboard) -> Dashboard {
+        // Variables are assumed to be topologically sorted, like in `variables_combinations`.
+        let mut changed_variables = HashSet::<&str>::default();
+        for var in self.variables() {
+            if previous.variables().find(|v| v.name == var.name) != Some(var)
+                || variables::referenced_variables(&var.query)
+                    .any(|name| changed_variables.contains(name))
+            {
+                changed_variables.insert(&var.name);
+            }
+        }
+        debug!(?changed_variables);
+        let previous_queries: HashMap<_, _> = previous
+            .queried_panels()
+            .map(|p| (p.key(), p.query_definitions()))
+            .collect();
+        let changed = |panels: &[Panel]| -> Vec<Panel> {
+            panels
+                .iter()
+                .filter(|panel| {
+                    previous_queries.get(&panel.key()) != Some(&panel.query_definitions())
+                        || panel.sql().any(|sql| {
+                            variables::referenced_variables(sql)
+                                .any(|name| changed_variables.contains(name))
+                        })
+                })
+                .cloned()
+                .collect()
+        };
+        Dashboard {
+            title: self.title.clone(),
+            version: self.version,
+            panels: changed(&self.panels),
+            annotations: changed(&self.annotations),
+            templating: self.templating.clone(),
+            time: self.time.clone(),
+        }
+    }
     pub fn variables(&self) -> impl DoubleEndedIterator<Item = &Variable> {
         self.templating.list.iter()
     }
     pub fn variables_sql(&self) -> impl Iterator<Item = &Variable> {
-        self.variables().filter(|v| v.is_clickhouse_ds())
+        self.variables()
+            .filter(|v| v.r#type == "query" && v.is_clickhouse_ds())
     }
-    // This is a bit inefficient, to be able to handle interdependent variables.
-    pub async fn variables_combinations(
-        &self,
-        variables_config: VariablesConfig,
-        client: &ChClient,
-    ) -> anyhow::Result<Vec<VariablesAssignment<'_>>> {
-        info!("Determining variables combinations");
-        let progress = indicatif::ProgressBar::with_draw_target(
-            Some(self.variables().count() as u64),
-            indicatif::ProgressDrawTarget::hidden(),
-        );
-        let mut combinations: Vec<VariablesAssignment> = vec![Default::default()];
-        for var in self.variables() {
-            info!(
-                var.name,
-                "Processing variable {}/{}, ETA {}",
-                progress.position(),
-                progress.length().unwrap(),
-                indicatif::HumanDuration(progress.eta())
-            );
-            let mut combinations2 = Vec::<VariablesAssignment>::default();
-            for assignment in &combinations {
-                // WARN: This heavily relies on the caching in the Clickhouse client to not rerun
+    /// Lazily enumerate the variables combinations, in lexicographic order.
+    ///
+    /// The variables are resolved depth-first, so that interdependent variables can be handled
+    /// without holding all the combinations in memory.
+    ///
+    /// Without an executor, the Clickhouse variables are resolved offline, from the values saved
+    /// in the dashboard.
+    ///
+    /// In the popular mode, all the combinations are enumerated, to be ranked with
+    /// [`crate::popularity::Popularity::rank`].
+    pub fn variables_combinations<'a, E: Executor + ?Sized>(
+        &'a self,
+        variables_config: &'a VariablesConfig,
+        selection: &'a Selection,
+        executor: Option<&'a E>,
+    ) -> BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>> {
+        info!(mode=?selection.mode, "Determining variables combinations");
+        // Ad hoc filters are not substituted with the `${varname}` syntax.
+        let variables: Vec<&Variable> = self.variables().filter(|v| v.r#type != "adhoc").collect();
+        let warmed = Arc::new(WarmedQueries::default());
+        let resolve = move |depth: usize, assignment: VariablesAssignment<'a>| {
+            let var = variables.get(depth).copied();
+            let warmed = warmed.clone();
+            async move {
+                let Some(var) = var else {
+                    return Ok::<_, anyhow::Error>(None);
+                };
+                // WARN: This heavily relies on the caching in the executor to not rerun
                 // the queries that have no dependency in some variables
-                let variants: Box<dyn Iterator<Item = String>> =
-                    // TODO: Would be nice to avoid the clone
-                    if let Some(variants) = variables_config.0.get(&var.name).cloned() {
-                        // NOTE: It could also make sense to skip the ones that are not part of the
-                        // query response.
-                        Box::new(variants.into_iter())
-                    } else {
-                        Box::new(var.get_variants(client, assignment).await?)
+                let variants = if let Some(variants) = variables_config.0.get(&var.name) {
+                    // NOTE: It could also make sense to skip the ones that are not part of the
+                    // query response.
+                    variants.iter().cloned().map(VariableValue::from).collect()
+                } else {
+                    var.select_variants(selection, executor, &assignment, &warmed)
+                        .await?
+                };
+                trace!(var.name, n_variants = variants.len(), "Resolved variable");
+                Ok(Some(Frame {
+                    var: var.name.as_str(),
+                    assignment,
+                    variants: variants.into_iter(),
+                }))
+            }
+        };
+        let combinations = futures::stream::try_unfold(None, move |stack| {
+            let resolve = resolve.clone();
+            async move {
+                let mut stack: Vec<Frame> = match stack {
+                    Some(stack) => stack,
+                    None => match resolve(0, Default::default()).await? {
+                        Some(frame) => vec![frame],
+                        // No variables
+                        None => return Ok(Some((Default::default(), Some(vec![])))),
+                    },
+                };
+                while let Some(frame) = stack.last_mut() {
+                    let Some(value) = frame.variants.next() else {
+                        stack.pop();
+                        continue;
                     };
-                for val in variants {
-                    let mut assignment2 = assignment.clone();
-                    assignment2.insert(var.name.as_str(), val);
-                    combinations2.push(assignment2);
+                    let mut assignment = frame.assignment.clone();
+                    assignment.insert(frame.var, value);
+                    match resolve(stack.len(), assignment.clone()).await? {
+                        Some(frame) => stack.push(frame),
+                        None => return Ok(Some((assignment, Some(stack)))),
+                    }
                 }
+                Ok(None)
             }
-            combinations = combinations2;
-            progress.inc(1);
-        }
-        Ok(combinations)
+        });
+        combinations.boxed()
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Variable queries already executed in the Native format in an enumeration, per data source uid
+/// and SQL. The variables are resolved again for each prefix of the combinations.
+type WarmedQueries = std::sync::Mutex<HashSet<(Option<String>, String)>>;
+
+/// Partial combination in the depth-first enumeration, with the values left to explore for the
+/// next variable.
+struct Frame<'a> {
+    var: &'a str,
+    assignment: VariablesAssignment<'a>,
+    variants: std::vec::IntoIter<VariableValue>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 struct TemplateList {
     list: Vec<Variable>,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Variable {
     pub name: String,
+    /// `query`, `custom`, `constant`, `textbox`, `interval`, `datasource`, `adhoc`, ...
+    #[serde(default = "default_variable_type")]
+    pub r#type: String,
+    #[serde(default)]
     pub query: String,
     #[serde(default)]
     options: Vec<VariableOption>,
+    current: Option<CurrentValue>,
     datasource: Option<DataSource>,
+    /// Custom value of the `All` option, e.g. `.*`, instead of all the values
+    #[serde(rename = "allValue")]
+    all_value: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_variable_type() -> String {
+    "query".into()
+}
+
+/// Saved selection of a variable. Multi-value variables store arrays.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+struct CurrentValue {
+    text: Option<OneOrMany>,
+    value: Option<OneOrMany>,
+}
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+impl OneOrMany {
+    fn values(&self) -> &[String] {
+        match self {
+            OneOrMany::One(s) => std::slice::from_ref(s),
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+/// Value of the `All` option, in the saved selection and the options
+const ALL_VALUE: &str = "$__all";
+
+/// Value of a multi-value selection, as substituted by the Clickhouse data sources: quoted and
+/// separated by commas, e.g. `'a','b'`.
+fn multi_value<'a>(values: impl IntoIterator<Item = &'a String>) -> String {
+    format!("'{}'", values.into_iter().join("','"))
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 struct VariableOption {
+    text: Option<String>,
     value: String,
 }
+impl VariableOption {
+    fn to_value(&self) -> VariableValue {
+        VariableValue::new(self.text.as_ref().unwrap_or(&self.value), &self.value)
+    }
+}
 
-#[derive(Debug, Deserialize)]
-struct DataSource {
-    r#type: String,
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "DataSourceRepr")]
+pub struct DataSource {
+    pub r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
 }
-#[derive(Debug, Deserialize)]
+// Older dashboards reference data sources by name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DataSourceRepr {
+    Ref {
+        #[serde(default)]
+        r#type: String,
+        uid: Option<String>,
+    },
+    Name(String),
+}
+impl From<DataSourceRepr> for DataSource {
+    fn from(repr: DataSourceRepr) -> Self {
+        match repr {
+            DataSourceRepr::Ref { r#type, uid } => Self { r#type, uid },
+            DataSourceRepr::Name(name) => {
+                debug!(
+                    name,
+                    "Data source referenced by name, which is not supported"
+                );
+                Self {
+                    r#type: Default::default(),
+                    uid: None,
+                }
+            }
+        }
+    }
+}
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Panel {
     #[serde(default)]
     pub title: String,
+    /// Absent from library panel models
+    #[serde(default)]
     pub id: u64,
     #[serde(default)]
     targets: Vec<Target>,
+    #[serde(default)]
     pub r#type: String,
+    #[serde(default)]
     pub grid_pos: GridPos,
+    pub datasource: Option<DataSource>,
+    /// Panels of a collapsed row
+    #[serde(default)]
+    panels: Vec<Panel>,
+    /// Reference to a library panel, whose model contains the queries
+    pub library_panel: Option<LibraryPanelRef>,
+    #[serde(skip)]
+    pub kind: PanelKind,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LibraryPanelRef {
+    pub uid: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// Library panel, as returned by `/api/library-elements/{uid}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LibraryPanel {
+    pub uid: String,
+    pub model: Panel,
+}
+#[derive(Deserialize)]
+struct LibraryPanelResponse {
+    result: LibraryPanel,
+}
+
+/// Library panels, by uid.
+#[derive(Debug, Default)]
+pub struct LibraryPanels(pub HashMap<String, LibraryPanel>);
+impl LibraryPanels {
+    /// Read library panels exported as JSON files, either as returned by the API or as bare
+    /// library elements.
+    pub fn from_dir(path: &std::path::Path) -> anyhow::Result<Self> {
+        let mut library = Self::default();
+        for entry in
+            std::fs::read_dir(path).with_context(|| format!("Could not read {:?}", path))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let data = std::fs::read_to_string(&path)?;
+            let element = serde_json::from_str::<LibraryPanelResponse>(&data)
+                .map(|r| r.result)
+                .or_else(|_| serde_json::from_str::<LibraryPanel>(&data))
+                .with_context(|| format!("Invalid library panel {:?}", path))?;
+            library.0.insert(element.uid.clone(), element);
+        }
+        info!(n = library.0.len(), "Read library panels from {:?}", path);
+        Ok(library)
+    }
 }
 impl std::fmt::Display for Panel {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Panel {} ({})", self.id, self.title)
+        write!(f, "{} {} ({})", self.kind, self.id, self.title)
+    }
+}
+
+/// Whether a `Panel` is an actual panel or an annotation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanelKind {
+    #[default]
+    Panel,
+    Annotation,
+}
+impl std::fmt::Display for PanelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PanelKind::Panel => write!(f, "Panel"),
+            PanelKind::Annotation => write!(f, "Annotation"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AnnotationList {
+    list: Vec<Annotation>,
+}
+/// Annotation query definition.
+#[derive(Debug, Deserialize)]
+struct Annotation {
+    #[serde(default)]
+    name: String,
+    #[serde(default = "default_enable")]
+    enable: bool,
+    datasource: Option<DataSource>,
+    target: Option<Target>,
+}
+fn default_enable() -> bool {
+    true
+}
+impl Annotation {
+    fn is_clickhouse_ds(&self) -> bool {
+        self.datasource
+            .as_ref()
+            .is_some_and(|ds| ds.r#type.contains("clickhouse"))
+    }
+    /// Panel with the annotation query as single target, identified by the annotation index.
+    fn into_panel(self, id: u64) -> Panel {
+        Panel {
+            title: self.name,
+            id,
+            targets: self.target.into_iter().collect(),
+            r#type: "annotation".into(),
+            grid_pos: Default::default(),
+            datasource: self.datasource,
+            panels: vec![],
+            library_panel: None,
+            kind: PanelKind::Annotation,
+        }
     }
 }
 
@@ -135,9 +555,31 @@ impl Panel {
     pub fn sql(&self) -> impl Iterator<Item = &String> {
         self.targets.iter().flat_map(|t| &t.raw_sql)
     }
+    /// Identifier of the panel, unique in the dashboard
+    pub fn key(&self) -> (PanelKind, u64) {
+        (self.kind, self.id)
+    }
+    /// Targets with an SQL query
+    pub fn queries(&self) -> impl Iterator<Item = &Target> {
+        self.targets.iter().filter(|t| t.raw_sql.is_some())
+    }
+    /// Targets with an SQL query, along with their data source
+    fn query_definitions(&self) -> Vec<(Option<&DataSource>, &Target)> {
+        self.queries()
+            .map(|t| (self.target_datasource(t), t))
+            .collect()
+    }
+    /// Data source of a target, falling back to the panel one.
+    pub fn target_datasource<'a>(&'a self, target: &'a Target) -> Option<&'a DataSource> {
+        target
+            .datasource
+            .as_ref()
+            .filter(|ds| ds.uid.is_some())
+            .or(self.datasource.as_ref())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct GridPos {
     pub x: u64,
     pub y: u64,
@@ -145,43 +587,488 @@ pub struct GridPos {
     pub h: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct Target {
+pub struct Target {
     raw_sql: Option<String>,
+    datasource: Option<DataSource>,
+    /// Remaining fields of the query model, sent as-is to Grafana
+    #[serde(flatten)]
+    model: serde_json::Map<String, serde_json::Value>,
+}
+impl Target {
+    pub fn sql(&self) -> Option<&String> {
+        self.raw_sql.as_ref()
+    }
+    /// Query model to send to `/api/ds/query`, with the given (substituted) SQL.
+    pub fn to_query(&self, sql: &str, datasource: &DataSource) -> serde_json::Value {
+        let mut model = self.model.clone();
+        model.insert("rawSql".into(), sql.into());
+        model.insert("datasource".into(), serde_json::json!(datasource));
+        serde_json::Value::Object(model)
+    }
+}
+
+/// Time range of the dashboard, in the Grafana syntax (e.g. `now-6h`).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimeRange {
+    pub from: String,
+    pub to: String,
+}
+impl Default for TimeRange {
+    fn default() -> Self {
+        Self {
+            from: "now-6h".into(),
+            to: "now".into(),
+        }
+    }
+}
+
+impl DataSource {
+    /// Same data source, with the uid substituted if it references a data source variable,
+    /// e.g. `${DS}` or `$DS`.
+    pub fn substitute(&self, variables: &VariablesAssignment<'_>) -> anyhow::Result<Self> {
+        let name = self.uid.as_deref().and_then(|uid| {
+            uid.strip_prefix("${")
+                .and_then(|u| u.strip_suffix('}'))
+                .or_else(|| uid.strip_prefix('$'))
+        });
+        let Some(name) = name else {
+            return Ok(self.clone());
+        };
+        let value = variables
+            .get(name)
+            .with_context(|| format!("Data source variable {} not found", name))?;
+        Ok(Self {
+            r#type: self.r#type.clone(),
+            uid: Some(value.value.clone()),
+        })
+    }
+}
+
+/// Client for the Grafana HTTP API.
+#[derive(Clone)]
+pub struct GrafanaClient {
+    client: reqwest_middleware::ClientWithMiddleware,
+    url: reqwest::Url,
+    token: Option<String>,
+    cache: Arc<tokio::sync::Mutex<QueryCache>>,
+}
+/// Rows of the queries executed with cache enabled, per data source uid and SQL
+type QueryCache = HashMap<(Option<String>, String), Table>;
+impl GrafanaClient {
+    /// Client for the Grafana instance at the given base URL, authenticating with a service
+    /// account token if provided.
+    pub fn new(url: reqwest::Url, token: Option<String>, tls: &TlsFlags) -> anyhow::Result<Self> {
+        let retry_policy =
+            reqwest_retry::policies::ExponentialBackoff::builder().build_with_max_retries(3);
+        let client = reqwest_middleware::ClientBuilder::new(tls.client()?)
+            .with(reqwest_retry::RetryTransientMiddleware::new_with_policy(
+                retry_policy,
+            ))
+            .build();
+        Ok(Self {
+            client,
+            url,
+            token,
+            cache: Default::default(),
+        })
+    }
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+    ) -> anyhow::Result<reqwest_middleware::RequestBuilder> {
+        let mut builder = self.client.request(method, self.url.join(path)?).header(
+            reqwest::header::USER_AGENT,
+            format!("ch-grafana-cache/{}", env!("CARGO_PKG_VERSION")),
+        );
+        if let Some(token) = &self.token {
+            builder = builder.bearer_auth(token);
+        }
+        Ok(builder)
+    }
+    async fn send(
+        builder: reqwest_middleware::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let resp = builder.send().await?;
+        if !resp.status().is_success() {
+            anyhow::bail!(
+                "{}: {}",
+                resp.status(),
+                resp.text().await.unwrap_or_default()
+            );
+        }
+        Ok(resp)
+    }
+    /// Discard the rows cached by the queries executed with cache enabled.
+    pub async fn clear_cache(&self) {
+        self.cache.lock().await.clear();
+    }
+    /// Retrieve a dashboard by uid
+    pub async fn dashboard(&self, uid: &str) -> anyhow::Result<Dashboard> {
+        info!("Retrieving dashboard {} from {}", uid, self.url);
+        let builder = self.request(reqwest::Method::GET, &format!("api/dashboards/uid/{}", uid))?;
+        Ok(Self::send(builder)
+            .await?
+            .json::<DashboardResponse>()
+            .await?
+            .dashboard)
+    }
+    /// Retrieve library panels by uid
+    pub async fn library_panels<'a>(
+        &self,
+        uids: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<LibraryPanels> {
+        let mut library = LibraryPanels::default();
+        for uid in uids {
+            if library.0.contains_key(uid) {
+                continue;
+            }
+            info!("Retrieving library panel {}", uid);
+            let builder = self.request(
+                reqwest::Method::GET,
+                &format!("api/library-elements/{}", uid),
+            )?;
+            let element = Self::send(builder)
+                .await
+                .with_context(|| format!("Failed to retrieve library panel {}", uid))?
+                .json::<LibraryPanelResponse>()
+                .await?
+                .result;
+            library.0.insert(uid.into(), element);
+        }
+        Ok(library)
+    }
+    /// Data source to execute a query on, substituted with the variables of the query.
+    fn datasource(query: &Query<'_>) -> anyhow::Result<DataSource> {
+        let datasource = query
+            .datasource
+            .context("Executing a query through Grafana requires a data source")?;
+        match query.variables {
+            Some(variables) => datasource.substitute(variables),
+            None => Ok(datasource.clone()),
+        }
+    }
+    /// Execute a query through its data source (`/api/ds/query`), with the model of its target if
+    /// any, and return the response along with its size in bytes.
+    ///
+    /// The variables are sent as `scopedVars`, as done by the Grafana frontend.
+    async fn ds_query(&self, query: Query<'_>) -> anyhow::Result<(DsQueryResponse, Response)> {
+        let datasource = &Self::datasource(&query)?;
+        let model = match query.target {
+            Some(target) => target.to_query(query.sql, datasource),
+            None => serde_json::json!({
+                "refId": "A",
+                "rawSql": query.sql,
+                // Table
+                "format": 1,
+                "datasource": datasource,
+            }),
+        };
+        let time = query.time.cloned().unwrap_or_default();
+        let scoped_vars: serde_json::Map<_, _> = query
+            .variables
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| {
+                let value = serde_json::json!({ "text": value.text, "value": value.value });
+                (name.to_string(), value)
+            })
+            .collect();
+        let body = serde_json::json!({
+            "queries": [model],
+            "from": time.from,
+            "to": time.to,
+            "scopedVars": scoped_vars,
+        });
+        let builder = self
+            .request(reqwest::Method::POST, "api/ds/query")?
+            .json(&body);
+        let resp = builder.send().await?;
+        let status = resp.status();
+        let cache_hit = resp
+            .headers()
+            .get("x-cache")
+            .and_then(|h| h.to_str().ok())
+            .map(|c| c == "HIT");
+        let resp = resp.bytes().await?;
+        let results = serde_json::from_slice::<DsQueryResponse>(&resp);
+        let error = results
+            .iter()
+            .flat_map(|r| &r.results)
+            .find_map(|(ref_id, result)| Some((ref_id, result.error.as_ref()?)));
+        if let Some((ref_id, error)) = error {
+            return Err(query_error(status, error))
+                .with_context(|| format!("Query {} failed", ref_id));
+        }
+        if !status.is_success() {
+            let body = String::from_utf8_lossy(&resp);
+            return Err(clickhouse::Error::new(status, None, &body).into());
+        }
+        let results = results?;
+        Ok((
+            results,
+            Response {
+                bytes: resp.len(),
+                cache_hit,
+                query_id: None,
+            },
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl Executor for GrafanaClient {
+    #[instrument(skip_all, fields(sql = query.sql))]
+    async fn query_rows(&self, query: Query<'_>, cache: bool) -> anyhow::Result<Table> {
+        let key = (Self::datasource(&query)?.uid, query.sql.to_string());
+        if cache {
+            if let Some(table) = self.cache.lock().await.get(&key) {
+                return Ok(table.clone());
+            }
+        }
+        let (results, _) = self.ds_query(query).await?;
+        let frame = results
+            .results
+            .into_values()
+            .flat_map(|r| r.frames)
+            .next()
+            .unwrap_or_default();
+        let table = frame.into_table()?;
+        if cache {
+            self.cache.lock().await.insert(key, table.clone());
+        }
+        Ok(table)
+    }
+    #[instrument(skip_all, fields(sql = query.sql))]
+    async fn query_discard(&self, query: Query<'_>) -> anyhow::Result<Response> {
+        Ok(self.ds_query(query).await?.1)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DsQueryResponse {
+    #[serde(default)]
+    results: HashMap<String, DsQueryResult>,
+}
+#[derive(Debug, Deserialize)]
+struct DsQueryResult {
+    error: Option<String>,
+    #[serde(default)]
+    frames: Vec<DataFrame>,
+}
+lazy_static::lazy_static! {
+    /// Clickhouse error code in the error of a data source query, e.g.
+    /// `code: 241, message: Memory limit exceeded` from the Clickhouse plugin
+    static ref EXCEPTION_CODE_RE: regex::Regex =
+        regex::Regex::new(r"(?i)\bcode: (\d+)\b").unwrap();
+}
+
+/// Error of a data source query, classified by its Clickhouse error code if any, so that
+/// `--keep-going` defers the transient ones as with the clickhouse backend.
+fn query_error(status: reqwest::StatusCode, error: &str) -> clickhouse::Error {
+    let code = EXCEPTION_CODE_RE
+        .captures(error)
+        .and_then(|c| c[1].parse().ok());
+    clickhouse::Error::new(status, code, error)
+}
+
+/// Data frame in the JSON encoding, with column-oriented values.
+#[derive(Debug, Default, Deserialize)]
+struct DataFrame {
+    #[serde(default)]
+    schema: DataFrameSchema,
+    #[serde(default)]
+    data: DataFrameData,
+}
+#[derive(Debug, Default, Deserialize)]
+struct DataFrameSchema {
+    #[serde(default)]
+    fields: Vec<DataFrameField>,
+}
+#[derive(Debug, Deserialize)]
+struct DataFrameField {
+    name: String,
+}
+#[derive(Debug, Default, Deserialize)]
+struct DataFrameData {
+    #[serde(default)]
+    values: Vec<Vec<serde_json::Value>>,
+}
+impl DataFrame {
+    fn into_table(self) -> anyhow::Result<Table> {
+        let names: Vec<String> = self.schema.fields.into_iter().map(|f| f.name).collect();
+        anyhow::ensure!(
+            names.len() == self.data.values.len(),
+            "Inconsistent column sizes"
+        );
+        let n_rows = self.data.values.first().map_or(0, |c| c.len());
+        anyhow::ensure!(
+            self.data.values.iter().all(|c| c.len() == n_rows),
+            "Inconsistent column sizes"
+        );
+        let rows = (0..n_rows)
+            .map(|i| ResultRow {
+                cols: self
+                    .data
+                    .values
+                    .iter()
+                    .map(|c| match &c[i] {
+                        serde_json::Value::String(s) => s.clone(),
+                        serde_json::Value::Null => String::new(),
+                        v => v.to_string(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(Table { names, rows })
+    }
 }
 
 impl Variable {
     fn is_clickhouse_ds(&self) -> bool {
         self.datasource
             .as_ref()
-            .map_or(false, |ds| ds.r#type.contains("clickhouse"))
+            .is_some_and(|ds| ds.r#type.contains("clickhouse"))
+    }
+    /// Saved selection of the variable, where a multi-value selection is a single value (see
+    /// [`multi_value`]).
+    ///
+    /// The `All` option is the custom all value if any, else all the `variants`, if given.
+    fn current_value(&self, variants: Option<&[VariableValue]>) -> Option<VariableValue> {
+        let current = self.current.as_ref()?;
+        let values = current.value.as_ref()?;
+        if values.values().iter().any(|v| v == ALL_VALUE) {
+            let value = match &self.all_value {
+                Some(all_value) => all_value.clone(),
+                None => multi_value(variants?.iter().map(|v| &v.value)),
+            };
+            return Some(VariableValue::new("All", value));
+        }
+        let texts = current.text.as_ref().map(|t| t.values());
+        match values {
+            OneOrMany::One(value) => {
+                let text = texts.and_then(|t| t.first()).unwrap_or(value);
+                Some(VariableValue::new(text, value))
+            }
+            OneOrMany::Many(values) if values.is_empty() => None,
+            OneOrMany::Many(values) => {
+                let texts = texts.filter(|t| t.len() == values.len()).unwrap_or(values);
+                Some(VariableValue::new(texts.join(" + "), multi_value(values)))
+            }
+        }
+    }
+    /// Whether all the values of the saved selection are among the variants.
+    fn current_available(&self, variants: &[VariableValue]) -> bool {
+        let Some(values) = self.current.as_ref().and_then(|c| c.value.as_ref()) else {
+            return false;
+        };
+        values
+            .values()
+            .iter()
+            .all(|value| value == ALL_VALUE || variants.iter().any(|v| &v.value == value))
+    }
+    /// Values of the options saved in the dashboard, without the `All` option.
+    fn option_values(&self) -> Box<dyn Iterator<Item = VariableValue> + '_> {
+        Box::new(
+            self.options
+                .iter()
+                .filter(|o| o.value != ALL_VALUE)
+                .map(|o| o.to_value()),
+        )
+    }
+    /// Values of the variable to use, according to the selection mode.
+    async fn select_variants<E: Executor + ?Sized>(
+        &self,
+        selection: &Selection,
+        executor: Option<&E>,
+        variables: &VariablesAssignment<'_>,
+        warmed: &WarmedQueries,
+    ) -> anyhow::Result<Vec<VariableValue>> {
+        if let (Mode::Current, Some(current)) = (selection.mode, self.current_value(None)) {
+            return Ok(vec![current]);
+        }
+        let variants = self.get_variants(executor, variables, warmed).await?;
+        Ok(match selection.mode {
+            Mode::All | Mode::Popular => variants.collect(),
+            Mode::TopN => variants.take(selection.top_n.unwrap_or(1)).collect(),
+            Mode::Current | Mode::Defaults => {
+                let variants: Vec<_> = variants.collect();
+                match self.current_value(Some(&variants)) {
+                    Some(current) if self.current_available(&variants) => vec![current],
+                    current => {
+                        if current.is_some() {
+                            debug!(variable = self.name, "Saved selection no longer available");
+                        }
+                        variants.into_iter().take(1).collect()
+                    }
+                }
+            }
+        })
+    }
+    /// Values saved in the dashboard, for Clickhouse variables resolved offline.
+    fn saved_variants(&self) -> anyhow::Result<Box<dyn Iterator<Item = VariableValue> + '_>> {
+        trace!(
+            var = self.query,
+            "Handling Clickhouse query variable offline"
+        );
+        if !self.options.is_empty() {
+            Ok(self.option_values())
+        } else if let Some(current) = self.current_value(None) {
+            Ok(Box::new(std::iter::once(current)))
+        } else {
+            anyhow::bail!(
+                "No saved values for variable {}, a Clickhouse client is required",
+                self.name
+            );
+        }
     }
     #[tracing::instrument(skip_all,fields(variable=self.name) )]
-    async fn get_variants(
+    async fn get_variants<E: Executor + ?Sized>(
         &self,
-        client: &ChClient,
+        executor: Option<&E>,
         variables: &VariablesAssignment<'_>,
-    ) -> anyhow::Result<Box<dyn Iterator<Item = String> + '_>> {
-        match &self.datasource {
-            Some(_) if self.is_clickhouse_ds() => {
+        warmed: &WarmedQueries,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = VariableValue> + '_>> {
+        match (&self.datasource, executor) {
+            (Some(_), None) if self.is_clickhouse_ds() => self.saved_variants(),
+            (Some(_), Some(executor)) if self.is_clickhouse_ds() => {
                 let query = variables::substitute_variables(&self.query, variables)?;
                 trace!(query, "Handling Clickhouse query variable");
+                let query = Query::variable(&query, self.datasource.as_ref(), variables);
 
                 // The trick is to enable caching to not re-run queries that are equivalent after
                 // substitution. With more effort, we could notice this before the substitution.
-                let resp = client.query(query, true).await?;
+                let resp = match executor.query_rows(query, true).await {
+                    // Offline, from the variables cache
+                    Err(e) if e.is::<CacheMiss>() => {
+                        debug!("{}", e);
+                        return self.saved_variants();
+                    }
+                    resp => resp?,
+                };
 
                 // For caching. It is a bit wasteful we have to do the query twice, but Grafana
                 // uses the native protocol, which is harder to parse.
-                client.query_native(self.query.clone()).await?;
+                let datasource = self.datasource.as_ref().map(|ds| ds.substitute(variables));
+                let key = (
+                    datasource.transpose()?.and_then(|ds| ds.uid),
+                    query.sql.into(),
+                );
+                if warmed.lock().unwrap().insert(key) {
+                    executor.query_discard(query).await?;
+                }
 
-                anyhow::ensure!(resp.first().map(|r| r.n_cols()).unwrap_or_default() <= 1);
-                Ok(Box::new(resp.into_iter().flat_map(|c| c.cols)))
+                Ok(Box::new(table_values(resp)?.into_iter()))
             }
-            None => {
+            (None, _) => {
                 trace!(var = self.query, "Handling JSON variable");
-                Ok(Box::new(self.options.iter().map(|o| o.value.clone())))
+                if self.options.is_empty() {
+                    // e.g. constant and data source variables
+                    return Ok(Box::new(self.current_value(None).into_iter()));
+                }
+                Ok(self.option_values())
             }
             _ => {
                 anyhow::bail!("Unsupported variable data source {:?}", self);
@@ -189,3 +1076,296 @@ impl Variable {
         }
     }
 }
+
+/// Values of a variable query response.
+///
+/// Following the Grafana convention, the `__text` and `__value` columns are used if present.
+/// Otherwise, the response must have a single column, used for both.
+fn table_values(table: Table) -> anyhow::Result<Vec<VariableValue>> {
+    let (value, text) = match (table.column("__value"), table.column("__text")) {
+        (Some(value), text) => (value, text.unwrap_or(value)),
+        (None, Some(text)) => (text, text),
+        (None, None) => {
+            anyhow::ensure!(
+                table.n_cols() <= 1,
+                "Variable queries must return a single column, or `__text` and `__value` columns (got {:?})",
+                table.names
+            );
+            (0, 0)
+        }
+    };
+    Ok(table
+        .rows
+        .into_iter()
+        .map(|r| VariableValue::new(&r.cols[text], &r.cols[value]))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use futures::TryStreamExt;
+
+    use crate::clickhouse::{ResultRow, Table};
+    use crate::executor::{Executor, Query, Response};
+    use crate::variables::VariableValue;
+
+    /// Executor answering variable queries from a fixed map, and recording the discarded ones.
+    struct MockExecutor(HashMap<&'static str, Table>, Mutex<Vec<String>>);
+    impl MockExecutor {
+        fn new(tables: HashMap<&'static str, Table>) -> Self {
+            Self(tables, Default::default())
+        }
+    }
+    #[async_trait::async_trait]
+    impl Executor for MockExecutor {
+        async fn query_rows(&self, query: Query<'_>, _cache: bool) -> anyhow::Result<Table> {
+            self.0
+                .get(query.sql)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Unexpected query {}", query.sql))
+        }
+        async fn query_discard(&self, query: Query<'_>) -> anyhow::Result<Response> {
+            self.1.lock().unwrap().push(query.sql.into());
+            Ok(Response::default())
+        }
+    }
+
+    fn table(names: &[&str], rows: &[&[&str]]) -> Table {
+        Table {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            rows: rows
+                .iter()
+                .map(|r| ResultRow {
+                    cols: r.iter().map(|c| c.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn table_values() -> anyhow::Result<()> {
+        assert_eq!(
+            super::table_values(table(&["name"], &[&["a"], &["b"]]))?,
+            vec![VariableValue::from("a"), VariableValue::from("b")]
+        );
+        assert_eq!(
+            super::table_values(table(&["__text", "__value"], &[&["first", "1"]]))?,
+            vec![VariableValue::new("first", "1")]
+        );
+        assert_eq!(
+            super::table_values(table(&["__value", "__text"], &[&["1", "first"]]))?,
+            vec![VariableValue::new("first", "1")]
+        );
+        assert!(super::table_values(table(&["a", "b"], &[&["1", "2"]])).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn variables_combinations() -> anyhow::Result<()> {
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "panels": [],
+            "templating": { "list": [
+                { "name": "a", "query": "SELECT a", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+                { "name": "b", "query": "SELECT b WHERE a = '${a}'", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+                { "name": "c", "query": "SELECT c", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+            ]}
+        }))?;
+        let executor = MockExecutor::new(HashMap::from([
+            ("SELECT a", table(&["a"], &[&["1"], &["2"]])),
+            ("SELECT b WHERE a = '1'", table(&["b"], &[&["x"]])),
+            ("SELECT b WHERE a = '2'", table(&["b"], &[&["y"], &["z"]])),
+            ("SELECT c", table(&["c"], &[&["0"]])),
+        ]));
+        let selection = super::Selection::new(super::Mode::All);
+        let config = super::VariablesConfig::default();
+        let combinations: Vec<_> = dashboard
+            .variables_combinations(&config, &selection, Some(&executor))
+            .map_ok(|c| format!("{}{}{}", c["a"], c["b"], c["c"]))
+            .try_collect()
+            .await?;
+        assert_eq!(combinations, vec!["1x0", "2y0", "2z0"]);
+        // Warmed once per query, although `c` is resolved for each prefix
+        let mut discarded = executor.1.into_inner().unwrap();
+        assert_eq!(discarded.len(), 4);
+        discarded.sort();
+        discarded.dedup();
+        assert_eq!(discarded.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saved_selection() -> anyhow::Result<()> {
+        let ds = serde_json::json!({ "type": "grafana-clickhouse-datasource", "uid": "ch" });
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "templating": { "list": [
+                { "name": "all", "query": "SELECT host", "datasource": ds,
+                  "current": { "text": ["All"], "value": ["$__all"] } },
+                { "name": "custom", "query": "SELECT host", "datasource": ds, "allValue": ".*",
+                  "current": { "text": "All", "value": "$__all" } },
+                { "name": "multi", "query": "SELECT host", "datasource": ds,
+                  "current": { "text": ["A", "B"], "value": ["a", "b"] } },
+                { "name": "stale", "query": "SELECT host", "datasource": ds,
+                  "current": { "text": ["a", "z"], "value": ["a", "z"] } },
+            ]}
+        }))?;
+        let executor = MockExecutor::new(HashMap::from([(
+            "SELECT host",
+            table(&["host"], &[&["a"], &["b"], &["c"]]),
+        )]));
+        let config = super::VariablesConfig::default();
+        let combinations = |mode| {
+            let (dashboard, config, executor) = (&dashboard, &config, &executor);
+            async move {
+                let selection = super::Selection::new(mode);
+                dashboard
+                    .variables_combinations(config, &selection, Some(executor))
+                    .map_ok(|c| {
+                        ["all", "custom", "multi", "stale"]
+                            .map(|var| c[var].value.clone())
+                            .join(" ")
+                    })
+                    .try_collect::<Vec<_>>()
+                    .await
+            }
+        };
+
+        assert_eq!(
+            combinations(super::Mode::Current).await?,
+            vec!["'a','b','c' .* 'a','b' 'a','z'"]
+        );
+        // The saved selection is no longer available for `stale`
+        assert_eq!(
+            combinations(super::Mode::Defaults).await?,
+            vec!["'a','b','c' .* 'a','b' a"]
+        );
+        // Single values
+        let all = combinations(super::Mode::All).await?;
+        assert_eq!(all.len(), 81);
+        assert_eq!(all[0], "a a a a");
+        assert_eq!(combinations(super::Mode::TopN).await?, vec!["a a a a"]);
+
+        let multi = dashboard.variables().nth(2).unwrap();
+        assert_eq!(multi.current_value(None).unwrap().text, "A + B");
+
+        // Offline, from the saved options
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "templating": { "list": [
+                { "name": "all", "query": "SELECT host", "datasource": ds,
+                  "current": { "text": "All", "value": "$__all" },
+                  "options": [
+                      { "text": "All", "value": "$__all" },
+                      { "text": "a", "value": "a" },
+                      { "text": "b", "value": "b" },
+                  ] },
+            ]}
+        }))?;
+        let selection = super::Selection::new(super::Mode::Current);
+        let combinations: Vec<_> = dashboard
+            .variables_combinations(&config, &selection, None::<&MockExecutor>)
+            .map_ok(|c| c["all"].value.clone())
+            .try_collect()
+            .await?;
+        assert_eq!(combinations, vec!["'a','b'"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adhoc_filters() -> anyhow::Result<()> {
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "templating": { "list": [
+                { "name": "a", "query": "SELECT a", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+                { "name": "filters", "type": "adhoc", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+            ]}
+        }))?;
+        let executor = MockExecutor::new(HashMap::from([(
+            "SELECT a",
+            table(&["a"], &[&["1"], &["2"]]),
+        )]));
+        let selection = super::Selection::new(super::Mode::All);
+        let config = super::VariablesConfig::default();
+        let combinations: Vec<_> = dashboard
+            .variables_combinations(&config, &selection, Some(&executor))
+            .map_ok(|c| {
+                assert_eq!(c.len(), 1);
+                c["a"].to_string()
+            })
+            .try_collect()
+            .await?;
+        assert_eq!(combinations, vec!["1", "2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rows() -> anyhow::Result<()> {
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "panels": [
+                { "id": 1, "type": "row", "collapsed": false, "panels": [] },
+                { "id": 2, "type": "timeseries" },
+                { "id": 3, "type": "row", "collapsed": true, "panels": [
+                    { "id": 4, "type": "table" },
+                ]},
+            ],
+            "rows": [{ "panels": [{ "id": 5, "type": "graph" }] }],
+        }))?;
+        let ids: Vec<u64> = dashboard.panels.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![2, 4, 5]);
+        assert_eq!(dashboard.variables().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn variables_sql() -> anyhow::Result<()> {
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "templating": { "list": [
+                { "name": "a", "query": "SELECT a", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+                { "name": "b", "type": "custom", "query": "x,y", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+                { "name": "c", "type": "textbox", "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" } },
+            ]}
+        }))?;
+        let names: Vec<&str> = dashboard.variables_sql().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["a"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saved_value_fallback() -> anyhow::Result<()> {
+        let dashboard: super::Dashboard = serde_json::from_value(serde_json::json!({
+            "title": "test",
+            "templating": { "list": [
+                { "name": "c", "type": "constant", "query": "10", "current": { "text": "10", "value": "10" } },
+                { "name": "ds", "type": "datasource", "query": "grafana-clickhouse-datasource", "current": { "text": "Clickhouse", "value": "ch" } },
+            ]}
+        }))?;
+        let selection = super::Selection::new(super::Mode::All);
+        let config = super::VariablesConfig::default();
+        let combinations: Vec<_> = dashboard
+            .variables_combinations(&config, &selection, None::<&MockExecutor>)
+            .map_ok(|c| format!("{} {}", c["c"].value, c["ds"].value))
+            .try_collect()
+            .await?;
+        assert_eq!(combinations, vec!["10 ch"]);
+        Ok(())
+    }
+
+    #[test]
+    fn data_frame() -> anyhow::Result<()> {
+        let frame: super::DataFrame = serde_json::from_value(serde_json::json!({
+            "schema": { "fields": [{ "name": "__text" }, { "name": "__value" }] },
+            "data": { "values": [["first", "second"], [1, null]] }
+        }))?;
+        assert_eq!(
+            frame.into_table()?,
+            table(&["__text", "__value"], &[&["first", "1"], &["second", ""]])
+        );
+        Ok(())
+    }
+}
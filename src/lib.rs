@@ -1,3 +1,13 @@
+pub mod cache;
+pub mod checkpoint;
 pub mod clickhouse;
+pub mod daemon;
+pub mod executor;
 pub mod grafana;
+pub mod metrics;
+pub mod popularity;
+pub mod throttle;
+pub mod tls;
 pub mod variables;
+pub mod verify;
+pub mod warmup;
@@ -1,14 +1,28 @@
+use std::collections::BTreeMap;
+use std::io::Write;
 use std::path::PathBuf;
 
 use anyhow::Context;
 use clap::Parser;
 use colored::Colorize;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use tracing::*;
 
+use ch_grafana_cache::cache::{CacheFlags, CachedExecutor};
+use ch_grafana_cache::checkpoint::{Checkpoint, CheckpointFlags};
 use ch_grafana_cache::clickhouse;
-use ch_grafana_cache::grafana::{self, VariablesConfig};
-use ch_grafana_cache::variables;
+use ch_grafana_cache::daemon;
+use ch_grafana_cache::executor::Executor;
+use ch_grafana_cache::grafana::{self, Selection, VariablesConfig};
+use ch_grafana_cache::metrics::{self, Metrics};
+use ch_grafana_cache::popularity::Popularity;
+use ch_grafana_cache::tls;
+use ch_grafana_cache::variables::VariablesAssignment;
+use ch_grafana_cache::verify;
+use ch_grafana_cache::warmup::{self, Budget, BudgetTracker};
+
+type Combinations<'a> = BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>;
 
 lazy_static::lazy_static! {
     static ref THEMES: Vec<String> =
@@ -24,12 +38,20 @@ struct Flags {
     /// Base Grafana URL
     #[clap(long, env = "GRAFANA_URL")]
     grafana_url: Option<reqwest::Url>,
+    /// Grafana service account token
+    #[clap(long, env = "GRAFANA_TOKEN", hide_env_values = true)]
+    grafana_token: Option<String>,
+    #[clap(flatten)]
+    grafana_tls: tls::GrafanaTlsFlags,
     /// Grafana dashboard id
-    #[clap(long, requires = "grafana")]
+    #[clap(long, requires = "grafana_url")]
     dashboard: Option<String>,
     /// Dashboard JSON file.
     #[clap(long, conflicts_with = "dashboard")]
     json: Option<PathBuf>,
+    /// Directory of library panels exported as JSON, used instead of retrieving them from Grafana
+    #[clap(long)]
+    library_panels: Option<PathBuf>,
     /// Synctect theme for syntax highlighting
     #[clap(long, env = "CH_GRAFANA_CACHE_THEME",
            value_parser=clap::builder::PossibleValuesParser::new(THEMES.iter().map(|s| s.as_str())))]
@@ -41,25 +63,41 @@ struct Flags {
     command: Command,
 }
 impl Flags {
+    fn grafana_client(&self) -> anyhow::Result<Option<grafana::GrafanaClient>> {
+        self.grafana_url
+            .clone()
+            .map(|url| {
+                let tls = tls::TlsFlags::from(&self.grafana_tls);
+                grafana::GrafanaClient::new(url, self.grafana_token.clone(), &tls)
+            })
+            .transpose()
+    }
     async fn get_dashboard(&self) -> anyhow::Result<grafana::Dashboard> {
-        match (&self.json, &self.grafana_url, &self.dashboard) {
-            (Some(json), _, _) => Ok(serde_json::from_str::<grafana::Dashboard>(
-                &std::fs::read_to_string(json)?,
-            )?),
-            (None, Some(grafana), Some(dashboard)) => {
-                info!("Retrieving dashboard from {}", grafana);
-                Ok(
-                    reqwest::get(grafana.join("api/dashboards/uid/")?.join(dashboard)?)
-                        .await?
-                        .json::<grafana::DashboardResponse>()
-                        .await?
-                        .dashboard,
-                )
+        let mut dashboard = match (&self.json, self.grafana_client()?, &self.dashboard) {
+            (Some(json), _, _) => {
+                serde_json::from_str::<grafana::Dashboard>(&std::fs::read_to_string(json)?)?
             }
+            (None, Some(grafana), Some(dashboard)) => grafana.dashboard(dashboard).await?,
             _ => {
                 anyhow::bail!("Use --json, or --grafana and --dashboard")
             }
+        };
+        if dashboard.library_panel_uids().next().is_some() {
+            let library = match (&self.library_panels, self.grafana_client()?) {
+                (Some(dir), _) => grafana::LibraryPanels::from_dir(dir)?,
+                (None, Some(grafana)) => {
+                    grafana
+                        .library_panels(dashboard.library_panel_uids())
+                        .await?
+                }
+                (None, None) => {
+                    warn!("The dashboard uses library panels, which require --grafana-url or --library-panels");
+                    Default::default()
+                }
+            };
+            dashboard.resolve_library_panels(&library);
         }
+        Ok(dashboard)
     }
 }
 
@@ -69,14 +107,323 @@ enum Command {
     Print,
     /// Execute the queries
     Execute {
+        /// Clickhouse server, required unless using the Grafana backend with variables resolved
+        /// offline
+        #[clap(flatten)]
+        flags: OptionalClickhouseFlags,
+        #[clap(flatten)]
+        combinations: CombinationsFlags,
+        #[clap(flatten)]
+        execution: warmup::ExecuteFlags,
+        #[clap(flatten)]
+        metrics: metrics::MetricsFlags,
+        #[clap(flatten)]
+        checkpoint: CheckpointFlags,
+    },
+    /// Execute the queries on a schedule, retrieving the dashboard again before each run
+    #[clap(alias = "daemon")]
+    Serve {
+        #[clap(flatten)]
+        flags: OptionalClickhouseFlags,
+        #[clap(flatten)]
+        combinations: CombinationsFlags,
+        #[clap(flatten)]
+        execution: warmup::ExecuteFlags,
+        #[clap(flatten)]
+        schedule: daemon::ScheduleFlags,
+        #[clap(flatten)]
+        metrics: metrics::MetricsFlags,
+        /// Serve the Prometheus metrics at `/metrics` on this address, e.g. `0.0.0.0:9185`
+        #[clap(long)]
+        metrics_listen: Option<std::net::SocketAddr>,
+        /// Poll the dashboard at this interval between the runs, e.g. `30s`, and execute the new
+        /// or modified queries right away
+        #[clap(long, value_parser = humantime::parse_duration)]
+        watch: Option<std::time::Duration>,
+    },
+    /// Execute the queries twice, and check that the second execution hits the cache
+    Verify {
         #[clap(flatten)]
         flags: clickhouse::Flags,
-        /// YAML file of the form variable_name: [ values ] to manually specify the values of some
-        /// variables in the dashboard
+        #[clap(flatten)]
+        combinations: CombinationsFlags,
+        /// Source of the cache status of the queries
+        #[clap(long, value_enum, default_value_t)]
+        method: verify::Method,
+    },
+    /// List the queries that would be executed, without executing them
+    Plan {
+        /// Clickhouse server to resolve the variables with. Without it, the values saved in the
+        /// dashboard are used.
+        #[clap(flatten)]
+        flags: OptionalClickhouseFlags,
+        #[clap(flatten)]
+        combinations: CombinationsFlags,
+        /// Export the queries to a JSON lines file rather than printing them
         #[clap(long)]
-        variables_yaml: Option<PathBuf>,
+        output: Option<PathBuf>,
     },
 }
+
+/// Clickhouse connection flags, present if `--url` or `--username` is given.
+///
+/// `Option<clickhouse::Flags>` cannot be flattened directly: clap does not track the presence of
+/// argument groups with flattened fields, and would always yield `None`. The flags are boxed to
+/// keep the size of the [`Command`] variants close.
+struct OptionalClickhouseFlags(Option<Box<clickhouse::Flags>>);
+impl clap::FromArgMatches for OptionalClickhouseFlags {
+    fn from_arg_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        Ok(Self(
+            if matches.contains_id("url") || matches.contains_id("username") {
+                Some(Box::new(clickhouse::Flags::from_arg_matches(matches)?))
+            } else {
+                None
+            },
+        ))
+    }
+    fn update_from_arg_matches(&mut self, matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+impl clap::Args for OptionalClickhouseFlags {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        // Otherwise, the throttling flags would be ignored without the other ones.
+        clickhouse::Flags::augment_args(cmd)
+            .mut_arg("url", |a| a.required(false))
+            .mut_arg("max_qps", |a| a.requires("url"))
+            .mut_arg("adaptive", |a| a.requires("url"))
+    }
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+/// Fail if the requests to Clickhouse are throttled while the panel queries go through Grafana,
+/// which bypasses the throttling.
+fn check_throttle(
+    flags: Option<&clickhouse::Flags>,
+    backend: warmup::BackendKind,
+) -> anyhow::Result<()> {
+    let throttled = flags.is_some_and(|f| f.throttle.max_qps.is_some() || f.throttle.adaptive);
+    anyhow::ensure!(
+        !throttled || backend == warmup::BackendKind::Clickhouse,
+        "--max-qps and --adaptive only apply to the clickhouse backend"
+    );
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CombinationsFlags {
+    /// YAML file of the form variable_name: [ values ] to manually specify the values of some
+    /// variables in the dashboard
+    #[clap(long)]
+    variables_yaml: Option<PathBuf>,
+    #[clap(flatten)]
+    selection: Selection,
+    #[clap(flatten)]
+    budget: Budget,
+    #[clap(flatten)]
+    cache: CacheFlags,
+}
+impl CombinationsFlags {
+    fn variables_config(&self, dashboard: &grafana::Dashboard) -> anyhow::Result<VariablesConfig> {
+        let variables_config = if let Some(variables_yaml) = &self.variables_yaml {
+            serde_yaml::from_str(
+                &std::fs::read_to_string(variables_yaml)
+                    .with_context(|| format!("Could not open {:?}", variables_yaml))?,
+            )?
+        } else {
+            VariablesConfig::default()
+        };
+        debug!(?variables_config);
+        variables_config.check(dashboard)?;
+        Ok(variables_config)
+    }
+    /// Enumerate the variables combinations, restricted by the budget.
+    async fn combinations<'a>(
+        &'a self,
+        dashboard: &'a grafana::Dashboard,
+        variables_config: &'a VariablesConfig,
+        executor: Option<&'a dyn Executor>,
+        tracker: &BudgetTracker,
+    ) -> anyhow::Result<(Combinations<'a>, Option<usize>)> {
+        let combinations =
+            dashboard.variables_combinations(variables_config, &self.selection, executor);
+        if self.selection.mode != grafana::Mode::Popular {
+            return self.budget.sample(combinations, tracker).await;
+        }
+        let executor = executor.context("The popular mode requires a Clickhouse client")?;
+        let popularity =
+            Popularity::from_query_log(executor, dashboard, self.selection.lookback).await?;
+        let limit = self
+            .selection
+            .top_n
+            .into_iter()
+            .chain(self.budget.max_combinations)
+            .min();
+        let ranked = popularity.rank(combinations, limit, tracker).await?;
+        let n_combinations = ranked.len();
+        Ok((
+            futures::stream::iter(ranked.into_iter().map(Ok)).boxed(),
+            Some(n_combinations),
+        ))
+    }
+}
+/// Clients and options of the warmups, shared by `execute` and `serve`.
+struct Warmup<'a> {
+    client: Option<&'a clickhouse::ChClient>,
+    grafana_client: Option<&'a grafana::GrafanaClient>,
+    combinations: &'a CombinationsFlags,
+    execution: &'a warmup::ExecuteFlags,
+    metrics: &'a Metrics,
+    metrics_flags: &'a metrics::MetricsFlags,
+    checkpoint: Option<&'a CheckpointFlags>,
+}
+impl Warmup<'_> {
+    /// Fail if the selected backend is not configured.
+    fn executor(&self) -> anyhow::Result<&dyn Executor> {
+        // Grafana has no data source to read the query log with.
+        anyhow::ensure!(
+            self.combinations.selection.mode != grafana::Mode::Popular || self.client.is_some(),
+            "--mode popular requires --url, to read the Clickhouse query log"
+        );
+        Ok(match self.execution.backend {
+            warmup::BackendKind::Clickhouse => self
+                .client
+                .context("The clickhouse backend requires --url")?,
+            warmup::BackendKind::Grafana => self
+                .grafana_client
+                .context("The grafana backend requires --grafana-url")?,
+        })
+    }
+    /// Execute the queries of the dashboard with the selected backend, recording them in the
+    /// metrics.
+    async fn execute(&self, dashboard: &grafana::Dashboard) -> anyhow::Result<warmup::Summary> {
+        let summary = self.execute_queries(dashboard).await;
+        if summary.as_ref().is_ok_and(|s| s.failed == 0) {
+            self.metrics.record_success(&dashboard.title);
+        }
+        self.write_metrics();
+        summary
+    }
+    fn write_metrics(&self) {
+        if let Some(path) = &self.metrics_flags.metrics_textfile {
+            // The outcome of the warmup matters more, e.g. to exit with its error.
+            if let Err(e) = self.metrics.write_textfile(path) {
+                error!("Failed to write the metrics to {:?}: {:?}", path, e);
+            }
+        }
+    }
+    async fn execute_queries(
+        &self,
+        dashboard: &grafana::Dashboard,
+    ) -> anyhow::Result<warmup::Summary> {
+        let variables_config = self.combinations.variables_config(dashboard)?;
+        let executor = self.executor()?;
+        let checkpoint = match self.checkpoint {
+            Some(flags) => Checkpoint::open(flags, dashboard)?,
+            None => None,
+        };
+        // The variables are resolved directly on Clickhouse when possible.
+        let variables_executor = self.client.map_or(executor, |c| c as &dyn Executor);
+        let variables_cache = self.combinations.cache.open()?;
+        let cached = variables_cache
+            .as_ref()
+            .map(|c| CachedExecutor::new(Some(variables_executor), c));
+        let variables_executor = cached
+            .as_ref()
+            .map_or(variables_executor, |c| c as &dyn Executor);
+
+        let mut tracker = self.combinations.budget.start();
+        let (combinations, n_combinations) = self
+            .combinations
+            .combinations(
+                dashboard,
+                &variables_config,
+                Some(variables_executor),
+                &tracker,
+            )
+            .await?;
+        info!(n_combinations, "Executing queries...");
+        let summary = warmup::execute(
+            dashboard,
+            combinations,
+            n_combinations,
+            executor,
+            self.execution,
+            &mut tracker,
+            warmup::Progress {
+                metrics: Some(self.metrics),
+                checkpoint,
+            },
+        )
+        .await;
+        if let Some(cache) = &variables_cache {
+            cache.save()?;
+        }
+        summary
+    }
+    /// Poll the dashboard until the given time, executing the new or modified queries.
+    async fn watch(
+        &self,
+        args: &Flags,
+        dashboard: &mut grafana::Dashboard,
+        period: std::time::Duration,
+        until: chrono::DateTime<chrono::Utc>,
+    ) {
+        while (until - chrono::Utc::now())
+            .to_std()
+            .is_ok_and(|remaining| remaining > period)
+        {
+            tokio::time::sleep(period).await;
+            let updated = match args.get_dashboard().await {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!("Failed to poll the dashboard: {:?}", e);
+                    continue;
+                }
+            };
+            let changes = updated.changes(dashboard);
+            let n_changed = changes.queried_panels().count();
+            if n_changed > 0 {
+                info!(
+                    from = dashboard.version,
+                    to = updated.version,
+                    n_changed,
+                    "Dashboard changed, executing the new queries"
+                );
+                // Only the full warmups count as successes in the metrics.
+                match self.execute_queries(&changes).await {
+                    Ok(summary) => info!(?summary, "Executed new queries"),
+                    Err(e) => error!("Warmup of the changes failed: {:?}", e),
+                }
+                self.write_metrics();
+            }
+            *dashboard = updated;
+        }
+    }
+}
+
+/// Serve the metrics in the Prometheus text format at `/metrics`.
+async fn serve_metrics(metrics: Metrics, addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Could not listen on {}", addr))?;
+    info!(%addr, "Serving metrics");
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || async move {
+            (
+                [(axum::http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                metrics.encode(),
+            )
+        }),
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     if let Err(e) = main_impl().await {
@@ -120,7 +467,8 @@ async fn main_impl() -> anyhow::Result<()> {
         dashboard.variables().map(|v| &v.name).join(", ")
     );
     debug!("{:#?}", dashboard);
-    match args.command {
+    let grafana_client = args.grafana_client()?;
+    match &args.command {
         Command::Print => {
             println!();
             println!("{}", "Variables:\n".yellow().bold());
@@ -128,74 +476,279 @@ async fn main_impl() -> anyhow::Result<()> {
                 println!("{}", var.name.yellow());
                 print_sql(&var.query, args.theme.as_ref())?;
             }
-            println!("{}", "Panels:\n".yellow().bold());
-            for panel in dashboard.panels {
-                if panel.sql().next().is_some() {
-                    println!("{}", panel.to_string().yellow());
+            for (heading, panels) in [
+                ("Panels:\n", &dashboard.panels),
+                ("Annotations:\n", &dashboard.annotations),
+            ] {
+                if panels.is_empty() {
+                    continue;
                 }
-                for sql in panel.sql() {
-                    print_sql(sql, args.theme.as_ref())?;
+                println!("{}", heading.yellow().bold());
+                for panel in panels {
+                    if panel.sql().next().is_some() {
+                        println!("{}", panel.to_string().yellow());
+                    }
+                    for sql in panel.sql() {
+                        print_sql(sql, args.theme.as_ref())?;
+                    }
                 }
             }
         }
         Command::Execute {
-            flags: ch_args,
-            variables_yaml,
+            flags: OptionalClickhouseFlags(ch_args),
+            combinations: combinations_args,
+            execution,
+            metrics: metrics_args,
+            checkpoint,
         } => {
-            let variables_config = if let Some(variables_yaml) = &variables_yaml {
-                serde_yaml::from_str(
-                    &std::fs::read_to_string(variables_yaml)
-                        .with_context(|| format!("Could not open {:?}", variables_yaml))?,
-                )?
-            } else {
-                VariablesConfig::default()
+            check_throttle(ch_args.as_deref(), execution.backend)?;
+            let client = ch_args
+                .as_deref()
+                .map(clickhouse::ChClient::from_flags)
+                .transpose()?;
+            let warmup = Warmup {
+                client: client.as_ref(),
+                grafana_client: grafana_client.as_ref(),
+                combinations: combinations_args,
+                execution,
+                metrics: &Metrics::default(),
+                metrics_flags: metrics_args,
+                checkpoint: Some(checkpoint),
+            };
+            let summary = warmup.execute(&dashboard).await?;
+            info!(?summary, "Executed queries");
+            anyhow::ensure!(summary.failed == 0, "{} queries failed", summary.failed);
+        }
+        Command::Serve {
+            flags: OptionalClickhouseFlags(ch_args),
+            combinations: combinations_args,
+            execution,
+            schedule: schedule_args,
+            metrics: metrics_args,
+            metrics_listen,
+            watch,
+        } => {
+            let schedule = schedule_args.schedule()?;
+            check_throttle(ch_args.as_deref(), execution.backend)?;
+            // The clients, and thus the variables values they cache, are kept across runs.
+            let client = ch_args
+                .as_deref()
+                .map(clickhouse::ChClient::from_flags)
+                .transpose()?;
+            let metrics = Metrics::default();
+            let warmup = Warmup {
+                client: client.as_ref(),
+                grafana_client: grafana_client.as_ref(),
+                combinations: combinations_args,
+                execution,
+                metrics: &metrics,
+                metrics_flags: metrics_args,
+                checkpoint: None,
+            };
+            // Fail early rather than on every run
+            warmup.executor()?;
+            let metrics_server = async {
+                match metrics_listen {
+                    Some(addr) => serve_metrics(metrics.clone(), *addr).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let serve = async {
+                let mut dashboard = dashboard;
+                let mut variables_cached = std::time::Instant::now();
+                for run in 1usize.. {
+                    let run_start = chrono::Utc::now();
+                    if run > 1 {
+                        match args.get_dashboard().await {
+                            Ok(d) => dashboard = d,
+                            Err(e) => error!(
+                                "Failed to refresh the dashboard, using the previous definition: {:?}",
+                                e
+                            ),
+                        }
+                    }
+                    let expired = match schedule_args.variables_ttl {
+                        Some(ttl) => variables_cached.elapsed() >= ttl,
+                        // Fresh values for each run, e.g. new hosts
+                        None => run > 1,
+                    };
+                    if expired {
+                        info!("Discarding the cached variables values");
+                        if let Some(client) = &client {
+                            client.clear_cache().await;
+                        }
+                        if let Some(client) = &grafana_client {
+                            client.clear_cache().await;
+                        }
+                        variables_cached = std::time::Instant::now();
+                    }
+                    let start = std::time::Instant::now();
+                    match warmup.execute(&dashboard).await {
+                        Ok(summary) => {
+                            info!(run, duration=?start.elapsed(), ?summary, "Executed queries")
+                        }
+                        Err(e) => error!(run, "Warmup failed: {:?}", e),
+                    }
+                    let next = schedule.next_run(run_start)?;
+                    info!(%next, "Waiting for the next warmup");
+                    if let Some(period) = watch {
+                        warmup.watch(&args, &mut dashboard, *period, next).await;
+                    }
+                    daemon::sleep_until(next).await;
+                }
+                anyhow::Ok(())
             };
-            debug!(?variables_config);
-            variables_config.check(&dashboard)?;
-            let client = clickhouse::ChClient::from_flags(&ch_args);
+            tokio::select! {
+                r = serve => r?,
+                r = metrics_server => r?,
+                r = daemon::shutdown_signal() => {
+                    r?;
+                    info!("Received shutdown signal, stopping");
+                }
+            }
+        }
+        Command::Verify {
+            flags: ch_args,
+            combinations: combinations_args,
+            method,
+        } => {
+            let variables_config = combinations_args.variables_config(&dashboard)?;
+            let client = clickhouse::ChClient::from_flags(ch_args)?;
+            let variables_cache = combinations_args.cache.open()?;
+            let cached = variables_cache
+                .as_ref()
+                .map(|c| CachedExecutor::new(Some(&client), c));
+            let variables_executor = cached.as_ref().map_or(&client as &dyn Executor, |c| c as _);
 
-            let combinations = dashboard
-                .variables_combinations(variables_config, &client)
+            let mut tracker = combinations_args.budget.start();
+            let (combinations, _) = combinations_args
+                .combinations(
+                    &dashboard,
+                    &variables_config,
+                    Some(variables_executor),
+                    &tracker,
+                )
                 .await?;
-
-            let n_combinations = combinations.len();
-            info!(
-                n_combinations,
-                "Variables combinations found. Executing queries...",
-            );
-            let progress = indicatif::ProgressBar::with_draw_target(
-                Some(n_combinations as u64),
-                indicatif::ProgressDrawTarget::hidden(),
-            );
-            for combination in combinations {
-                let span = span!(Level::INFO, "combination", ?combination);
-                let _span = span.enter();
-                info!(
-                    "Executing combination {}/{}, ETA {}.",
-                    progress.position() + 1,
-                    progress.length().unwrap(),
-                    indicatif::HumanDuration(progress.eta())
+            let reports =
+                verify::verify(&dashboard, combinations, &client, *method, &mut tracker).await?;
+            if let Some(cache) = &variables_cache {
+                cache.save()?;
+            }
+            println!("{}", "Cache status per panel:\n".yellow().bold());
+            for ((kind, id), report) in &reports {
+                let status = format!(
+                    "{} hits, {} misses, {} unknown",
+                    report.hits, report.misses, report.unknown
                 );
-                debug!(?combination);
+                let status = if report.misses > 0 || report.unknown > 0 {
+                    status.red()
+                } else {
+                    status.green()
+                };
+                println!("{} {} ({}): {}", kind, id, report.title, status);
+            }
+            let misses: usize = reports.values().map(|r| r.misses + r.unknown).sum();
+            anyhow::ensure!(
+                misses == 0,
+                "{} queries were not served from the cache",
+                misses
+            );
+        }
+        Command::Plan {
+            flags: OptionalClickhouseFlags(ch_args),
+            combinations: combinations_args,
+            output,
+        } => {
+            let variables_config = combinations_args.variables_config(&dashboard)?;
+            let client = ch_args
+                .as_deref()
+                .map(clickhouse::ChClient::from_flags)
+                .transpose()?;
+            let client = client.as_ref().map(|c| c as &dyn Executor);
+            // With the variables cache, the cached variables are resolved offline.
+            let variables_cache = combinations_args.cache.open()?;
+            let cached = variables_cache
+                .as_ref()
+                .map(|c| CachedExecutor::new(client, c));
+            let variables_executor = cached.as_ref().map(|c| c as _).or(client);
 
-                let mut bytes = 0;
-                for panel in &dashboard.panels {
-                    for sql in panel.sql() {
-                        let sql = variables::substitute_variables(sql, &combination)?;
-                        let panel_bytes =
-                            client.query_native(sql.clone()).await.with_context(|| {
-                                format!("Failed to run query [{}] in panel {}", sql, panel)
-                            })?;
-                        debug!(panel_id = panel.id, panel_size = panel_bytes);
-                        bytes += panel_bytes;
+            let mut tracker = combinations_args.budget.start();
+            let (mut combinations, _) = combinations_args
+                .combinations(&dashboard, &variables_config, variables_executor, &tracker)
+                .await?;
+            let mut output = output
+                .as_ref()
+                .map(|o| {
+                    std::fs::File::create(o)
+                        .map(std::io::BufWriter::new)
+                        .with_context(|| format!("Could not create {:?}", o))
+                })
+                .transpose()?;
+            let mut n_combinations = 0;
+            let mut panel_counts = BTreeMap::<_, (&grafana::Panel, usize)>::default();
+            'combinations: while let Some(combination) = combinations.try_next().await? {
+                n_combinations += 1;
+                if output.is_none() {
+                    println!(
+                        "{}",
+                        format!("Combination {}: {:?}\n", n_combinations, combination)
+                            .yellow()
+                            .bold()
+                    );
+                }
+                for (panel, _, sql) in warmup::panel_queries(&dashboard, &combination) {
+                    if tracker.exhausted() {
+                        warn!("Budget exhausted, stopping");
+                        break 'combinations;
+                    }
+                    let sql = sql?;
+                    tracker.record_query();
+                    panel_counts.entry(panel.key()).or_insert((panel, 0)).1 += 1;
+                    if let Some(output) = &mut output {
+                        serde_json::to_writer(
+                            &mut *output,
+                            &warmup::PlannedQuery {
+                                combination: &combination,
+                                kind: panel.kind,
+                                panel_id: panel.id,
+                                panel_title: &panel.title,
+                                sql,
+                            },
+                        )?;
+                        writeln!(output)?;
+                    } else {
+                        println!("{}", panel.to_string().yellow());
+                        print_sql(&sql, args.theme.as_ref())?;
                     }
                 }
-                info!(duration=?start.elapsed(), total_size=bytes, "Executed combination");
-                progress.inc(1);
             }
+            if let Some(mut output) = output {
+                output.flush()?;
+            }
+            if let Some(cache) = &variables_cache {
+                cache.save()?;
+            }
+            println!("{}", "Queries per panel:\n".yellow().bold());
+            for (panel, count) in panel_counts.values() {
+                println!("{}: {}", panel, count);
+            }
+            println!(
+                "\n{} combinations, {} queries",
+                n_combinations,
+                panel_counts.values().map(|(_, c)| c).sum::<usize>()
+            );
         }
     }
     info!(duration=?start.elapsed(), "Done");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn flags() {
+        use clap::CommandFactory;
+        super::Flags::command().debug_assert();
+    }
+}
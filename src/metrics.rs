@@ -0,0 +1,181 @@
+//! Prometheus metrics of the warmups.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+
+use super::executor::Response;
+use super::grafana::Panel;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct MetricsFlags {
+    /// Write the metrics to this file after each warmup, in the Prometheus text format (e.g. for
+    /// the node exporter textfile collector)
+    #[clap(long)]
+    pub metrics_textfile: Option<PathBuf>,
+}
+
+/// Metrics of the executed queries, labelled by dashboard.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    queries: IntCounterVec,
+    failures: IntCounterVec,
+    bytes: IntCounterVec,
+    cache: IntCounterVec,
+    duration: HistogramVec,
+    last_success: GaugeVec,
+}
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new().expect("valid metrics")
+    }
+}
+impl Metrics {
+    fn new() -> anyhow::Result<Self> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace("ch_grafana_cache");
+        let queries = IntCounterVec::new(
+            opts("queries_total", "Executed panel queries"),
+            &["dashboard"],
+        )?;
+        let failures = IntCounterVec::new(
+            opts("query_failures_total", "Failed panel queries"),
+            &["dashboard"],
+        )?;
+        let bytes = IntCounterVec::new(
+            opts(
+                "response_bytes_total",
+                "Size of the responses to the panel queries",
+            ),
+            &["dashboard"],
+        )?;
+        let cache = IntCounterVec::new(
+            opts(
+                "cache_responses_total",
+                "Panel queries per cache status, from the X-Cache header",
+            ),
+            &["dashboard", "status"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::from(opts(
+                "query_duration_seconds",
+                "Duration of the panel queries",
+            ))
+            .buckets(prometheus::exponential_buckets(0.01, 2.0, 14)?),
+            &["dashboard", "kind", "panel"],
+        )?;
+        let last_success = GaugeVec::new(
+            opts(
+                "last_success_timestamp_seconds",
+                "Unix time of the last successful warmup",
+            ),
+            &["dashboard"],
+        )?;
+        let registry = Registry::new();
+        registry.register(Box::new(queries.clone()))?;
+        registry.register(Box::new(failures.clone()))?;
+        registry.register(Box::new(bytes.clone()))?;
+        registry.register(Box::new(cache.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(last_success.clone()))?;
+        Ok(Self {
+            registry,
+            queries,
+            failures,
+            bytes,
+            cache,
+            duration,
+            last_success,
+        })
+    }
+    /// Record the execution of a panel query.
+    pub fn record_query(
+        &self,
+        dashboard: &str,
+        panel: &Panel,
+        duration: Duration,
+        response: Option<&Response>,
+    ) {
+        self.duration
+            .with_label_values(&[
+                dashboard,
+                &panel.kind.to_string().to_lowercase(),
+                &panel.id.to_string(),
+            ])
+            .observe(duration.as_secs_f64());
+        let Some(response) = response else {
+            self.failures.with_label_values(&[dashboard]).inc();
+            return;
+        };
+        self.queries.with_label_values(&[dashboard]).inc();
+        self.bytes
+            .with_label_values(&[dashboard])
+            .inc_by(response.bytes as u64);
+        if let Some(hit) = response.cache_hit {
+            self.cache
+                .with_label_values(&[dashboard, if hit { "hit" } else { "miss" }])
+                .inc();
+        }
+    }
+    /// Record the completion of a warmup.
+    pub fn record_success(&self, dashboard: &str) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_success
+            .with_label_values(&[dashboard])
+            .set(now.as_secs_f64());
+    }
+    /// Metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        prometheus::TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+    /// Write the metrics to a file, atomically so that collectors never read a partial file.
+    pub fn write_textfile(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.encode())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Could not write metrics to {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record() -> anyhow::Result<()> {
+        let metrics = Metrics::default();
+        let panel: Panel = serde_json::from_value(serde_json::json!({
+            "id": 3, "title": "Errors", "type": "table"
+        }))?;
+        let response = Response {
+            bytes: 100,
+            cache_hit: Some(true),
+            query_id: None,
+        };
+        metrics.record_query("Logs", &panel, Duration::from_millis(30), Some(&response));
+        metrics.record_query("Logs", &panel, Duration::from_millis(30), Some(&response));
+        metrics.record_query("Logs", &panel, Duration::from_secs(1), None);
+        metrics.record_success("Logs");
+
+        let text = metrics.encode();
+        for line in [
+            r#"ch_grafana_cache_queries_total{dashboard="Logs"} 2"#,
+            r#"ch_grafana_cache_query_failures_total{dashboard="Logs"} 1"#,
+            r#"ch_grafana_cache_response_bytes_total{dashboard="Logs"} 200"#,
+            r#"ch_grafana_cache_cache_responses_total{dashboard="Logs",status="hit"} 2"#,
+            r#"ch_grafana_cache_query_duration_seconds_count{dashboard="Logs",kind="panel",panel="3"} 3"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{}\n{}", line, text);
+        }
+        assert!(
+            text.contains("ch_grafana_cache_last_success_timestamp_seconds{dashboard=\"Logs\"}")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! Popularity of variables combinations, inferred from the Clickhouse query log.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::time::Duration;
+
+use futures::stream::{BoxStream, TryStreamExt};
+use itertools::Itertools;
+use tracing::*;
+
+use super::executor::{Executor, Query};
+use super::grafana::Dashboard;
+use super::variables::VariablesAssignment;
+use super::warmup::BudgetTracker;
+
+lazy_static::lazy_static! {
+    /// Variables in Grafana queries: `${var}` or `${var:format}`, `[[var]]` or `[[var:format]]`,
+    /// and `$var`. Names starting with `__` are global variables, e.g. `${__from}`, or macros of
+    /// the data source, e.g. `$__timeFilter(ts)`.
+    static ref TEMPLATE_RE: regex::Regex =
+        regex::Regex::new(r"\$\{(\w+)(?::[^}]*)?\}|\[\[(\w+)(?::[^\]]*)?\]\]|\$(\w+)").unwrap();
+}
+
+/// Values of (some of) the variables, as observed in an executed query.
+type Observation = BTreeMap<String, String>;
+
+/// Query with variables, matching its executions after substitution.
+struct QueryTemplate {
+    regex: regex::Regex,
+    variables: Vec<String>,
+}
+impl QueryTemplate {
+    fn new(sql: &str) -> anyhow::Result<Self> {
+        let sql = sql.trim();
+        let mut pattern = String::from("^");
+        let mut variables = vec![];
+        let mut last = 0;
+        while let Some(cap) = TEMPLATE_RE.captures_at(sql, last) {
+            let m = cap.get(0).unwrap();
+            let name = cap.iter().skip(1).flatten().next().unwrap().as_str();
+            pattern.push_str(&regex::escape(&sql[last..m.start()]));
+            last = m.end();
+            // Macros and global variables expand to values unrelated to the dashboard variables,
+            // and formatted values, e.g. quoted, cannot be compared to them.
+            if name.starts_with("__") || m.as_str().contains(':') {
+                if cap.get(3).is_some() {
+                    last = arguments_end(sql, last);
+                }
+                pattern.push_str(".*?");
+            } else {
+                pattern.push_str("(.*?)");
+                variables.push(name.to_string());
+            }
+        }
+        pattern.push_str(&regex::escape(&sql[last..]));
+        pattern.push('$');
+        Ok(Self {
+            regex: regex::RegexBuilder::new(&pattern)
+                .dot_matches_new_line(true)
+                .build()?,
+            variables,
+        })
+    }
+    /// Variables values in an executed query, if it matches the template.
+    fn observe(&self, query: &str) -> Option<Observation> {
+        let caps = self.regex.captures(query.trim())?;
+        let mut observation = Observation::new();
+        for (var, m) in self.variables.iter().zip(caps.iter().skip(1)) {
+            let value = m?.as_str();
+            // The same variable can appear several times, with necessarily the same value.
+            if let Some(previous) = observation.insert(var.clone(), value.into()) {
+                if previous != value {
+                    return None;
+                }
+            }
+        }
+        Some(observation)
+    }
+}
+
+/// End of the parenthesized arguments of a macro, e.g. `(toDateTime(ts))`, if any at `start`.
+fn arguments_end(sql: &str, start: usize) -> usize {
+    if !sql[start..].starts_with('(') {
+        return start;
+    }
+    let mut depth = 0;
+    for (i, c) in sql[start..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return start + i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    start
+}
+
+/// Number of executions of the dashboard queries, per observed variables values.
+#[derive(Debug, Default)]
+pub struct Popularity(HashMap<Observation, u64>);
+impl Popularity {
+    /// Infer the variables values from the panel queries in `system.query_log`, over the given
+    /// period. The queries sent by this tool are ignored, from their query id (see
+    /// [`crate::clickhouse::ChClient`]), as the user agent is Grafana's with the grafana-compat
+    /// profile.
+    pub async fn from_query_log<E: Executor + ?Sized>(
+        executor: &E,
+        dashboard: &Dashboard,
+        lookback: Duration,
+    ) -> anyhow::Result<Self> {
+        let templates = dashboard
+            .queried_panels()
+            .flat_map(|p| p.sql())
+            .map(|sql| QueryTemplate::new(sql))
+            .filter_ok(|template| !template.variables.is_empty())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        info!(
+            n_templates = templates.len(),
+            ?lookback,
+            "Retrieving queries from the query log"
+        );
+        let query = format!(
+            "SELECT query, count() AS c FROM system.query_log
+            WHERE type = 'QueryFinish' AND query_kind = 'Select' AND is_initial_query
+              AND event_time > now() - INTERVAL {} SECOND
+              AND NOT startsWith(query_id, 'ch-grafana-cache-')
+            GROUP BY query ORDER BY c DESC LIMIT 100000",
+            lookback.as_secs()
+        );
+        let mut popularity = Self::default();
+        for row in executor
+            .query_rows(Query::internal(&query), false)
+            .await?
+            .rows
+        {
+            let [query, count] = row.cols.as_slice() else {
+                anyhow::bail!("Unexpected query log response {:?}", row);
+            };
+            let count: u64 = count.parse()?;
+            for observation in templates.iter().filter_map(|t| t.observe(query)) {
+                *popularity.0.entry(observation).or_default() += count;
+            }
+        }
+        info!(
+            n_observations = popularity.0.len(),
+            "Inferred variables values from the query log"
+        );
+        Ok(popularity)
+    }
+    /// Number of executed queries consistent with the variables assignment.
+    pub fn score(&self, assignment: &VariablesAssignment<'_>) -> u64 {
+        self.0
+            .iter()
+            .filter(|(observation, _)| {
+                observation.iter().all(|(var, value)| {
+                    assignment
+                        .get(var.as_str())
+                        .is_some_and(|v| &v.value == value)
+                })
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+    /// The observed combinations, i.e. with a positive score, by decreasing score and then in
+    /// the enumeration order, up to `limit`. Only `limit` combinations are held in memory, and
+    /// the enumeration stops once the time budget is exhausted.
+    pub async fn rank<'a>(
+        &self,
+        mut combinations: BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>,
+        limit: Option<usize>,
+        tracker: &BudgetTracker,
+    ) -> anyhow::Result<Vec<VariablesAssignment<'a>>> {
+        // Min-heap of the best combinations so far
+        let mut best = BinaryHeap::new();
+        let mut n_combinations = 0;
+        while let Some(combination) = combinations.try_next().await? {
+            if tracker.exhausted() {
+                warn!("Time budget exhausted while ranking the combinations");
+                break;
+            }
+            let score = self.score(&combination);
+            if score > 0 {
+                best.push(Reverse(Ranked {
+                    score,
+                    index: n_combinations,
+                    combination,
+                }));
+                if limit.is_some_and(|limit| best.len() > limit) {
+                    best.pop();
+                }
+            }
+            n_combinations += 1;
+        }
+        info!(
+            n_combinations,
+            n_observed = best.len(),
+            "Ordered combinations by popularity"
+        );
+        Ok(best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(r)| r.combination)
+            .collect())
+    }
+}
+
+/// Combination ordered by score, then by reverse enumeration order.
+struct Ranked<'a> {
+    score: u64,
+    index: usize,
+    combination: VariablesAssignment<'a>,
+}
+impl Ranked<'_> {
+    fn key(&self) -> (u64, Reverse<usize>) {
+        (self.score, Reverse(self.index))
+    }
+}
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl Eq for Ranked<'_> {}
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    #[test]
+    fn observe() -> anyhow::Result<()> {
+        let template =
+            super::QueryTemplate::new("SELECT * FROM ${table} WHERE a = '${a}' AND b = '${a}'")?;
+        let observation = template
+            .observe("SELECT * FROM logs WHERE a = 'x' AND b = 'x'\n")
+            .unwrap();
+        assert_eq!(observation.get("table").unwrap(), "logs");
+        assert_eq!(observation.get("a").unwrap(), "x");
+        // Inconsistent values
+        assert!(template
+            .observe("SELECT * FROM logs WHERE a = 'x' AND b = 'y'")
+            .is_none());
+        assert!(template.observe("SELECT 1").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn score() {
+        let observation = |values: &[(&str, &str)]| {
+            values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<super::Observation>()
+        };
+        let popularity = super::Popularity(HashMap::from([
+            (observation(&[("a", "1")]), 3),
+            (observation(&[("a", "1"), ("b", "x")]), 2),
+            (observation(&[("a", "2")]), 5),
+        ]));
+        let score = |values: &[(&'static str, &str)]| {
+            popularity.score(&values.iter().map(|(k, v)| (*k, (*v).into())).collect())
+        };
+        assert_eq!(score(&[("a", "1"), ("b", "x")]), 5);
+        assert_eq!(score(&[("a", "1"), ("b", "y")]), 3);
+        assert_eq!(score(&[("a", "2"), ("b", "x")]), 5);
+        assert_eq!(score(&[("a", "3"), ("b", "x")]), 0);
+    }
+
+    #[test]
+    fn template_syntax() -> anyhow::Result<()> {
+        let template = super::QueryTemplate::new(
+            "SELECT $__timeInterval(ts) AS t, count() FROM $table
+            WHERE $__timeFilter(toDateTime(ts)) AND host IN ([[host]]) AND env = ${env:singlequote}
+              AND ts > $__fromTime AND ts < ${__to:date:seconds}
+            GROUP BY t",
+        )?;
+        assert_eq!(template.variables, vec!["table", "host"]);
+        let observation = template
+            .observe(
+                "SELECT toStartOfInterval(ts, INTERVAL 60 second) AS t, count() FROM logs
+            WHERE ts >= toDateTime(1700000000) AND ts <= toDateTime(1700003600) AND host IN ('a','b') AND env = 'prod'
+              AND ts > toDateTime(1700000000) AND ts < 1700003600
+            GROUP BY t",
+            )
+            .unwrap();
+        assert_eq!(observation.get("table").unwrap(), "logs");
+        assert_eq!(observation.get("host").unwrap(), "'a','b'");
+        assert!(!observation.contains_key("env"));
+        Ok(())
+    }
+}
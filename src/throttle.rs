@@ -0,0 +1,165 @@
+//! Rate limiting of the Clickhouse requests, slowing down when the server is overloaded.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::*;
+
+use super::clickhouse;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ThrottleFlags {
+    /// Maximum number of requests per second sent to Clickhouse, including the retries
+    #[clap(long, value_parser = parse_qps)]
+    pub max_qps: Option<f64>,
+    /// Slow down when Clickhouse is overloaded, i.e. when queries fail with errors such as
+    /// TOO_MANY_SIMULTANEOUS_QUERIES or MEMORY_LIMIT_EXCEEDED, or respond slower than
+    /// --adaptive-latency. The rate goes back up once the responses are healthy again.
+    #[clap(long)]
+    pub adaptive: bool,
+    /// Response time above which Clickhouse is considered overloaded, in adaptive mode
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    pub adaptive_latency: Duration,
+}
+fn parse_qps(s: &str) -> anyhow::Result<f64> {
+    let qps: f64 = s.parse()?;
+    anyhow::ensure!(qps > 0.0, "The rate must be positive");
+    Ok(qps)
+}
+
+/// Delay added after the first overload signal, and below which the delay is removed
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct State {
+    /// Earliest start of the next request
+    next: Instant,
+    /// Minimum interval between the requests added in adaptive mode, doubled on each overload
+    /// signal and reduced by a quarter on each healthy response
+    backoff: Duration,
+}
+
+/// Middleware spacing out the requests, to be registered after the retry middleware so that
+/// each attempt is throttled.
+#[derive(Debug)]
+pub struct Throttle {
+    /// Minimum interval between the requests, from the maximum rate
+    interval: Duration,
+    /// Latency threshold, if adaptive
+    max_latency: Option<Duration>,
+    state: Mutex<State>,
+}
+impl Throttle {
+    /// Throttle configured by the flags, if any limit is set.
+    pub fn from_flags(flags: &ThrottleFlags) -> Option<Self> {
+        if flags.max_qps.is_none() && !flags.adaptive {
+            return None;
+        }
+        Some(Self {
+            interval: flags
+                .max_qps
+                .map(|qps| Duration::from_secs_f64(1.0 / qps))
+                .unwrap_or_default(),
+            max_latency: flags.adaptive.then_some(flags.adaptive_latency),
+            state: Mutex::new(State {
+                next: Instant::now(),
+                backoff: Duration::ZERO,
+            }),
+        })
+    }
+    /// Reserve the next slot, returning its start.
+    fn reserve(&self) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let start = state.next.max(Instant::now());
+        state.next = start + self.interval.max(state.backoff);
+        start
+    }
+    /// Adapt the rate to the outcome of a request.
+    fn observe(&self, overloaded: bool, latency: Duration) {
+        let Some(max_latency) = self.max_latency else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        if overloaded || latency > max_latency {
+            let backoff = (state.backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+            if backoff != state.backoff {
+                warn!(
+                    overloaded,
+                    ?latency,
+                    ?backoff,
+                    "Clickhouse is overloaded, slowing down"
+                );
+            }
+            state.backoff = backoff;
+        } else if !state.backoff.is_zero() {
+            state.backoff = state.backoff * 3 / 4;
+            if state.backoff < MIN_BACKOFF {
+                info!("Clickhouse recovered, back to the full rate");
+                state.backoff = Duration::ZERO;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl reqwest_middleware::Middleware for Throttle {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: reqwest_middleware::Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let start = self.reserve();
+        tokio::time::sleep_until(start.into()).await;
+        let start = Instant::now();
+        let res = next.run(req, extensions).await;
+        let overloaded = match &res {
+            Ok(resp) => clickhouse::is_overloaded(resp),
+            Err(e) => e.is_timeout(),
+        };
+        self.observe(overloaded, start.elapsed());
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adaptive() {
+        let flags = ThrottleFlags {
+            max_qps: Some(20.0),
+            adaptive: true,
+            adaptive_latency: Duration::from_secs(1),
+        };
+        let throttle = Throttle::from_flags(&flags).unwrap();
+        let backoff = || throttle.state.lock().unwrap().backoff;
+        let fast = Duration::from_millis(10);
+
+        let start = throttle.reserve();
+        assert_eq!(throttle.reserve() - start, Duration::from_millis(50));
+
+        throttle.observe(true, fast);
+        assert_eq!(backoff(), MIN_BACKOFF);
+        throttle.observe(false, Duration::from_secs(2));
+        assert_eq!(backoff(), MIN_BACKOFF * 2);
+        let start = throttle.reserve();
+        assert_eq!(throttle.reserve() - start, MIN_BACKOFF * 2);
+
+        throttle.observe(false, fast);
+        assert_eq!(backoff(), Duration::from_millis(150));
+        throttle.observe(false, fast);
+        assert_eq!(backoff(), Duration::from_micros(112_500));
+        throttle.observe(false, fast);
+        assert_eq!(backoff(), Duration::ZERO);
+
+        for _ in 0..20 {
+            throttle.observe(true, fast);
+        }
+        assert_eq!(backoff(), MAX_BACKOFF);
+
+        assert!(Throttle::from_flags(&ThrottleFlags::default()).is_none());
+        assert!(parse_qps("0").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! TLS options of the HTTP clients.
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::*;
+
+/// TLS options of the Clickhouse client.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct TlsFlags {
+    /// PEM file of the certificate authorities to trust, in addition to the system ones
+    #[clap(long, env = "CLICKHOUSE_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file of the client certificate, for mutual TLS
+    #[clap(long, env = "CLICKHOUSE_CLIENT_CERT", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM file of the private key of the client certificate
+    #[clap(long, env = "CLICKHOUSE_CLIENT_KEY", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    /// Do not verify the server certificate. Insecure, for testing only.
+    #[clap(long)]
+    pub insecure: bool,
+}
+
+/// TLS options of the Grafana client, see [`TlsFlags`].
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct GrafanaTlsFlags {
+    /// PEM file of the certificate authorities to trust for Grafana, in addition to the system
+    /// ones
+    #[clap(long, env = "GRAFANA_CA_CERT")]
+    pub grafana_ca_cert: Option<PathBuf>,
+    /// PEM file of the client certificate for Grafana, for mutual TLS
+    #[clap(long, env = "GRAFANA_CLIENT_CERT", requires = "grafana_client_key")]
+    pub grafana_client_cert: Option<PathBuf>,
+    /// PEM file of the private key of the Grafana client certificate
+    #[clap(long, env = "GRAFANA_CLIENT_KEY", requires = "grafana_client_cert")]
+    pub grafana_client_key: Option<PathBuf>,
+    /// Do not verify the Grafana server certificate. Insecure, for testing only.
+    #[clap(long)]
+    pub grafana_insecure: bool,
+}
+impl From<&GrafanaTlsFlags> for TlsFlags {
+    fn from(flags: &GrafanaTlsFlags) -> Self {
+        Self {
+            ca_cert: flags.grafana_ca_cert.clone(),
+            client_cert: flags.grafana_client_cert.clone(),
+            client_key: flags.grafana_client_key.clone(),
+            insecure: flags.grafana_insecure,
+        }
+    }
+}
+
+impl TlsFlags {
+    /// HTTP client with the TLS options, using rustls.
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().use_rustls_tls();
+        if let Some(path) = &self.ca_cert {
+            let certs = reqwest::Certificate::from_pem_bundle(&read(path)?)
+                .with_context(|| format!("Invalid CA certificates in {:?}", path))?;
+            anyhow::ensure!(!certs.is_empty(), "No CA certificate in {:?}", path);
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let mut pem = read(cert)?;
+            pem.push(b'\n');
+            pem.extend(read(key)?);
+            let identity = reqwest::Identity::from_pem(&pem).with_context(|| {
+                format!("Invalid client certificate {:?} or key {:?}", cert, key)
+            })?;
+            builder = builder.identity(identity);
+        }
+        if self.insecure {
+            warn!("The server certificate is not verified");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Could not read {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client() {
+        let fixture = |name: &str| {
+            Some(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join(format!("tests/fixtures/tls/{}", name)),
+            )
+        };
+        let flags = TlsFlags {
+            ca_cert: fixture("ca.pem"),
+            client_cert: fixture("client.pem"),
+            client_key: fixture("client.key"),
+            insecure: false,
+        };
+        flags.client().unwrap();
+        TlsFlags::from(&GrafanaTlsFlags {
+            grafana_insecure: true,
+            ..Default::default()
+        })
+        .client()
+        .unwrap();
+
+        // The key is not a certificate
+        let err = TlsFlags {
+            ca_cert: fixture("client.key"),
+            ..Default::default()
+        }
+        .client()
+        .unwrap_err();
+        assert!(err.to_string().contains("No CA certificate"), "{:#}", err);
+        let err = TlsFlags {
+            ca_cert: fixture("missing.pem"),
+            ..Default::default()
+        }
+        .client()
+        .unwrap_err();
+        assert!(err.to_string().contains("Could not read"), "{:#}", err);
+        // Missing key
+        assert!(TlsFlags {
+            client_key: fixture("client.pem"),
+            ..flags
+        }
+        .client()
+        .is_err());
+    }
+}
@@ -1,12 +1,54 @@
 use std::collections::HashMap;
 
 use itertools::Itertools;
+use serde::Serialize;
 
 lazy_static::lazy_static! {
     pub static ref VARIABLE_RE: regex::Regex = regex::Regex::new(r#"\$\{(.*?)\}"#).unwrap();
 }
 
-pub type VariablesAssignment<'a> = HashMap<&'a str, String>;
+/// Value of a variable: the text displayed in Grafana, and the value substituted in queries.
+///
+/// These differ when a variable query returns `__text` and `__value` columns.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct VariableValue {
+    pub text: String,
+    pub value: String,
+}
+impl VariableValue {
+    pub fn new(text: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            value: value.into(),
+        }
+    }
+}
+impl From<String> for VariableValue {
+    fn from(value: String) -> Self {
+        Self {
+            text: value.clone(),
+            value,
+        }
+    }
+}
+impl From<&str> for VariableValue {
+    fn from(value: &str) -> Self {
+        Self::from(value.to_string())
+    }
+}
+impl std::fmt::Display for VariableValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+// Logs show the text, as in the Grafana UI.
+impl std::fmt::Debug for VariableValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.text)
+    }
+}
+
+pub type VariablesAssignment<'a> = HashMap<&'a str, VariableValue>;
 
 #[derive(thiserror::Error, Debug)]
 enum SubsError {
@@ -22,8 +64,13 @@ fn substitute_variable(
     variables
         .get(name)
         .ok_or_else(|| SubsError::NotFound(name.into()))
-        .cloned()
-        .map(|s| s.to_string())
+        .map(|v| v.value.clone())
+}
+/// Names of the variables referenced by a query
+pub fn referenced_variables(sql: &str) -> impl Iterator<Item = &str> {
+    VARIABLE_RE
+        .captures_iter(sql)
+        .map(|cap| cap.get(1).unwrap().as_str())
 }
 pub fn substitute_variables(
     sql: &str,
@@ -59,6 +106,14 @@ mod test {
                 &std::collections::HashMap::from([("table", "test".into())])
             )?
         );
+        // Text and value
+        assert_eq!(
+            "SELECT * FROM test WHERE id = 1",
+            super::substitute_variables(
+                "SELECT * FROM test WHERE id = ${id}",
+                &std::collections::HashMap::from([("id", super::VariableValue::new("first", "1"))])
+            )?
+        );
         // Missing variable
         assert!(super::substitute_variables("${table}", &Default::default()).is_err());
         Ok(())
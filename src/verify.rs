@@ -0,0 +1,121 @@
+//! Verification that the executed queries are served from the cache.
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use futures::stream::{BoxStream, TryStreamExt};
+use itertools::Itertools;
+use tracing::*;
+
+use super::executor::{Executor, Query};
+use super::grafana::{Dashboard, PanelKind};
+use super::variables::VariablesAssignment;
+use super::warmup::{self, BudgetTracker};
+
+/// Source of the cache status of the queries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Method {
+    /// `X-Cache` header set by chproxy
+    #[default]
+    Chproxy,
+    /// `query_cache_usage` column of the Clickhouse `system.query_log` table
+    QueryLog,
+}
+
+/// Cache status of the queries of a panel (or annotation), on their second execution.
+#[derive(Debug, Default)]
+pub struct PanelReport {
+    pub title: String,
+    pub hits: usize,
+    pub misses: usize,
+    /// Queries whose cache status could not be determined
+    pub unknown: usize,
+}
+
+/// Execute every panel query twice, and report whether the second execution hit the cache.
+pub async fn verify<'a, E: Executor + ?Sized>(
+    dashboard: &'a Dashboard,
+    mut combinations: BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>,
+    executor: &E,
+    method: Method,
+    tracker: &mut BudgetTracker,
+) -> anyhow::Result<BTreeMap<(PanelKind, u64), PanelReport>> {
+    let mut reports = BTreeMap::<(PanelKind, u64), PanelReport>::default();
+    // Query ids of the second executions, per panel
+    let mut query_ids = Vec::<((PanelKind, u64), String)>::default();
+    'combinations: while let Some(combination) = combinations.try_next().await? {
+        debug!(?combination, "Verifying combination");
+        for (panel, target, sql) in warmup::panel_queries(dashboard, &combination) {
+            if tracker.exhausted() {
+                warn!("Budget exhausted, stopping");
+                break 'combinations;
+            }
+            let sql = sql?;
+            let report = reports.entry(panel.key()).or_insert_with(|| PanelReport {
+                title: panel.title.clone(),
+                ..Default::default()
+            });
+            let query = Query::panel(&sql, dashboard, panel, target, &combination);
+            executor.query_discard(query).await?;
+            let resp = executor
+                .query_discard(query)
+                .await
+                .with_context(|| format!("Failed to run query [{}] in panel {}", sql, panel))?;
+            tracker.record_query();
+            match (method, resp.cache_hit, resp.query_id) {
+                (Method::Chproxy, Some(true), _) => report.hits += 1,
+                (Method::Chproxy, Some(false), _) => {
+                    debug!(sql, panel_id = panel.id, "Cache miss");
+                    report.misses += 1
+                }
+                (Method::QueryLog, _, Some(query_id)) => query_ids.push((panel.key(), query_id)),
+                _ => report.unknown += 1,
+            }
+        }
+    }
+    if method == Method::QueryLog && !query_ids.is_empty() {
+        let usage = query_cache_usage(executor, query_ids.iter().map(|(_, id)| id)).await?;
+        for (panel, query_id) in &query_ids {
+            let report = reports.get_mut(panel).unwrap();
+            match usage.get(query_id).map(|u| u.as_str()) {
+                Some("Read") => report.hits += 1,
+                Some(_) => report.misses += 1,
+                None => report.unknown += 1,
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Retrieve the `query_cache_usage` of the given queries from `system.query_log`.
+async fn query_cache_usage<E: Executor + ?Sized>(
+    executor: &E,
+    query_ids: impl Iterator<Item = &String>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    executor
+        .query_rows(Query::internal("SYSTEM FLUSH LOGS"), false)
+        .await?;
+    let mut usage = BTreeMap::default();
+    for chunk in &query_ids.chunks(1000) {
+        let ids = chunk
+            .map(|id| format!("'{}'", id.replace('\\', "\\\\").replace('\'', "\\'")))
+            .join(",");
+        // The query ids are deterministic, hence reused by the previous runs: the latest
+        // execution is kept.
+        let query = format!(
+            "SELECT query_id, query_cache_usage FROM system.query_log
+            WHERE type = 'QueryFinish' AND query_id IN ({})
+            ORDER BY event_time_microseconds",
+            ids
+        );
+        let rows = executor
+            .query_rows(Query::internal(&query), false)
+            .await?
+            .rows;
+        for row in rows {
+            let [query_id, cache_usage] = <[String; 2]>::try_from(row.cols)
+                .map_err(|cols| anyhow::anyhow!("Unexpected query log response {:?}", cols))?;
+            usage.insert(query_id, cache_usage);
+        }
+    }
+    Ok(usage)
+}
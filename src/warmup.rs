@@ -0,0 +1,430 @@
+//! Execution of the dashboard queries over the variables combinations.
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use tracing::*;
+
+use super::checkpoint::Checkpoint;
+use super::clickhouse::{self, Estimate};
+use super::executor::{Executor, Query};
+use super::grafana::{Dashboard, Panel, PanelKind, Target};
+use super::metrics::Metrics;
+use super::variables::{self, VariablesAssignment};
+
+/// Options for the execution of the queries.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ExecuteFlags {
+    /// Estimate the rows and marks read by each query with `EXPLAIN ESTIMATE` before executing it
+    #[clap(long)]
+    pub estimate: bool,
+    /// Skip the queries estimated to read more rows (implies --estimate)
+    #[clap(long)]
+    pub max_estimated_rows: Option<u64>,
+    /// Where to execute the panel queries
+    #[clap(long, value_enum, default_value_t)]
+    pub backend: BackendKind,
+    /// Continue after a failed panel query, and report the failures at the end. The queries
+    /// failing with a transient error (e.g. timeout or memory limit) are retried once after the
+    /// other ones.
+    #[clap(long)]
+    pub keep_going: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// Directly on the Clickhouse HTTP interface
+    #[default]
+    Clickhouse,
+    /// Through the Grafana data sources (`/api/ds/query`), exercising the Grafana-side caching
+    Grafana,
+}
+
+/// Limits on the work performed by a warmup.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Budget {
+    /// Maximum number of variables combinations. If more are available, a deterministic sample
+    /// is taken (see --seed).
+    #[clap(long)]
+    pub max_combinations: Option<usize>,
+    /// Maximum number of panel queries to execute
+    #[clap(long)]
+    pub max_queries: Option<usize>,
+    /// Maximum duration of the warmup, e.g. `30m`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub time_budget: Option<Duration>,
+    /// Seed for the sampling of the variables combinations
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+}
+impl Budget {
+    pub fn start(&self) -> BudgetTracker {
+        BudgetTracker {
+            deadline: self.time_budget.map(|d| Instant::now() + d),
+            max_queries: self.max_queries,
+            queries: 0,
+        }
+    }
+    /// Restrict the combinations to at most `max_combinations`, with a uniform sample taken
+    /// with reservoir sampling, and returned in the enumeration order. Returns the number of
+    /// combinations when known.
+    pub async fn sample<'a>(
+        &self,
+        combinations: BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>,
+        tracker: &BudgetTracker,
+    ) -> anyhow::Result<(
+        BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>,
+        Option<usize>,
+    )> {
+        let Some(max) = self.max_combinations else {
+            return Ok((combinations, None));
+        };
+        info!(max, seed = self.seed, "Sampling variables combinations");
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(self.seed);
+        let mut reservoir = Vec::with_capacity(max);
+        let mut n_combinations = 0;
+        let mut combinations = combinations;
+        while let Some(combination) = combinations.try_next().await? {
+            if tracker.exhausted() {
+                warn!("Time budget exhausted while enumerating the combinations");
+                break;
+            }
+            if reservoir.len() < max {
+                reservoir.push((n_combinations, combination));
+            } else {
+                let j = rng.gen_range(0..=n_combinations);
+                if j < max {
+                    reservoir[j] = (n_combinations, combination);
+                }
+            }
+            n_combinations += 1;
+        }
+        reservoir.sort_by_key(|(i, _)| *i);
+        info!(
+            n_combinations,
+            n_sampled = reservoir.len(),
+            "Sampled variables combinations"
+        );
+        let n_sampled = reservoir.len();
+        Ok((
+            futures::stream::iter(reservoir.into_iter().map(|(_, c)| Ok(c))).boxed(),
+            Some(n_sampled),
+        ))
+    }
+}
+
+/// Consumption of a `Budget`.
+#[derive(Debug)]
+pub struct BudgetTracker {
+    deadline: Option<Instant>,
+    max_queries: Option<usize>,
+    queries: usize,
+}
+impl BudgetTracker {
+    pub fn record_query(&mut self) {
+        self.queries += 1;
+    }
+    pub fn exhausted(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+            || self.max_queries.is_some_and(|m| self.queries >= m)
+    }
+}
+
+/// Panel and annotation queries for a variables combination, after substitution.
+pub fn panel_queries<'a: 'b, 'b>(
+    dashboard: &'a Dashboard,
+    combination: &'b VariablesAssignment<'_>,
+) -> impl Iterator<Item = (&'a Panel, &'a Target, anyhow::Result<String>)> + 'b {
+    dashboard.queried_panels().flat_map(move |panel| {
+        panel.queries().map(move |target| {
+            let sql = target.sql().expect("queries have SQL");
+            (
+                panel,
+                target,
+                variables::substitute_variables(sql, combination),
+            )
+        })
+    })
+}
+
+/// Query that would be executed for a variables combination.
+#[derive(Debug, Serialize)]
+pub struct PlannedQuery<'a> {
+    pub combination: &'a VariablesAssignment<'a>,
+    pub kind: PanelKind,
+    pub panel_id: u64,
+    pub panel_title: &'a str,
+    pub sql: String,
+}
+
+/// Recording of the progress of an execution.
+#[derive(Default)]
+pub struct Progress<'a> {
+    pub metrics: Option<&'a Metrics>,
+    /// Checkpoint of the executed queries, which are skipped
+    pub checkpoint: Option<Checkpoint>,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub combinations: usize,
+    pub queries: usize,
+    pub bytes: usize,
+    /// Queries skipped due to their estimate
+    pub skipped: usize,
+    /// Queries skipped as executed before the checkpoint
+    pub resumed: usize,
+    /// Queries that failed, with --keep-going
+    pub failed: usize,
+    pub estimate: Estimate,
+}
+
+/// Execute the panel queries for each variables combination, until the budget is exhausted,
+/// recording the progress.
+pub async fn execute<'a>(
+    dashboard: &'a Dashboard,
+    mut combinations: BoxStream<'a, anyhow::Result<VariablesAssignment<'a>>>,
+    n_combinations: Option<usize>,
+    executor: &dyn Executor,
+    flags: &ExecuteFlags,
+    tracker: &mut BudgetTracker,
+    mut progress: Progress<'_>,
+) -> anyhow::Result<Summary> {
+    let estimate = flags.estimate || flags.max_estimated_rows.is_some();
+    // Grafana would run `EXPLAIN ESTIMATE` through the data source, if at all.
+    anyhow::ensure!(
+        !estimate || flags.backend == BackendKind::Clickhouse,
+        "Estimating the queries requires the clickhouse backend"
+    );
+    let start = Instant::now();
+    let progress_bar = indicatif::ProgressBar::with_draw_target(
+        n_combinations.map(|n| n as u64),
+        indicatif::ProgressDrawTarget::hidden(),
+    );
+    let mut summary = Summary::default();
+    // Queries that failed with a transient error, with --keep-going
+    let mut deferred = vec![];
+    // Estimates of the queries already estimated in this run, e.g. in other combinations
+    let mut estimates = HashMap::new();
+    'combinations: while let Some(combination) = combinations.try_next().await? {
+        let span = span!(Level::INFO, "combination", ?combination);
+        let _span = span.enter();
+        match progress_bar.length() {
+            Some(n) => info!(
+                "Executing combination {}/{}, ETA {}.",
+                progress_bar.position() + 1,
+                n,
+                indicatif::HumanDuration(progress_bar.eta())
+            ),
+            None => info!("Executing combination {}.", progress_bar.position() + 1),
+        }
+        debug!(?combination);
+
+        let mut bytes = 0;
+        let mut panel_estimates = BTreeMap::<(PanelKind, u64), Estimate>::default();
+        for (panel, target, sql) in panel_queries(dashboard, &combination) {
+            if tracker.exhausted() {
+                warn!(?summary, "Budget exhausted, stopping");
+                break 'combinations;
+            }
+            let sql = sql?;
+            if progress
+                .checkpoint
+                .as_ref()
+                .is_some_and(|c| c.is_executed(&sql))
+            {
+                summary.resumed += 1;
+                continue;
+            }
+            let query = Query::panel(&sql, dashboard, panel, target, &combination);
+            let panel_bytes = match run_query(
+                executor,
+                dashboard,
+                panel,
+                query,
+                flags,
+                &mut estimates,
+                &progress,
+            )
+            .await
+            {
+                Ok(Outcome::Executed(bytes, query_estimate)) => {
+                    if let Some(query_estimate) = query_estimate {
+                        *panel_estimates.entry(panel.key()).or_default() += query_estimate;
+                    }
+                    bytes
+                }
+                Ok(Outcome::Skipped) => {
+                    summary.skipped += 1;
+                    continue;
+                }
+                Err(e) if flags.keep_going => {
+                    tracker.record_query();
+                    if clickhouse::Error::is_transient_error(&e) {
+                        warn!(
+                            panel_id = panel.id,
+                            error = format!("{:#}", e),
+                            "Query failed, retrying it later"
+                        );
+                        deferred.push((panel, target, sql, combination.clone()));
+                    } else {
+                        error!(%panel, error = format!("{:#}", e), sql, "Query failed");
+                        summary.failed += 1;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    // Keep the queries executed so far for a later --resume.
+                    if let Some(checkpoint) = &mut progress.checkpoint {
+                        checkpoint.save(summary.combinations)?;
+                    }
+                    return Err(e).with_context(|| {
+                        format!("Failed to run query [{}] in panel {}", sql, panel)
+                    });
+                }
+            };
+            debug!(panel_id = panel.id, panel_size = panel_bytes);
+            bytes += panel_bytes;
+            if let Some(checkpoint) = &mut progress.checkpoint {
+                checkpoint.record_query(&sql);
+                checkpoint.save_throttled(summary.combinations)?;
+            }
+            tracker.record_query();
+            summary.queries += 1;
+        }
+        if estimate {
+            let mut combination_estimate = Estimate::default();
+            for ((kind, panel_id), panel_estimate) in panel_estimates {
+                debug!(%kind, panel_id, ?panel_estimate);
+                combination_estimate += panel_estimate;
+            }
+            info!(?combination_estimate);
+            summary.estimate += combination_estimate;
+        }
+        info!(duration=?start.elapsed(), total_size=bytes, "Executed combination");
+        summary.bytes += bytes;
+        summary.combinations += 1;
+        progress_bar.inc(1);
+        if let Some(checkpoint) = &mut progress.checkpoint {
+            checkpoint.save_throttled(summary.combinations)?;
+        }
+    }
+    if !deferred.is_empty() {
+        info!(
+            n = deferred.len(),
+            "Retrying the queries that failed with a transient error"
+        );
+    }
+    for (panel, target, sql, combination) in deferred {
+        if tracker.exhausted() {
+            summary.failed += 1;
+            continue;
+        }
+        tracker.record_query();
+        let query = Query::panel(&sql, dashboard, panel, target, &combination);
+        match run_query(
+            executor,
+            dashboard,
+            panel,
+            query,
+            flags,
+            &mut estimates,
+            &progress,
+        )
+        .await
+        {
+            Ok(Outcome::Skipped) => summary.skipped += 1,
+            Ok(Outcome::Executed(bytes, _)) => {
+                summary.bytes += bytes;
+                summary.queries += 1;
+                if let Some(checkpoint) = &mut progress.checkpoint {
+                    checkpoint.record_query(&sql);
+                    checkpoint.save_throttled(summary.combinations)?;
+                }
+            }
+            Err(e) => {
+                error!(%panel, error = format!("{:#}", e), sql, "Query failed again");
+                summary.failed += 1;
+            }
+        }
+    }
+    if let Some(mut checkpoint) = progress.checkpoint {
+        // Keep the failed queries for a later --resume.
+        if tracker.exhausted() || summary.failed > 0 {
+            checkpoint.save(summary.combinations)?;
+        } else {
+            checkpoint.finish()?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Outcome of a panel query.
+enum Outcome {
+    /// Executed, with the size of the response and the estimate if requested
+    Executed(usize, Option<Estimate>),
+    /// Skipped as estimated to read too many rows
+    Skipped,
+}
+
+/// Estimate the panel query if requested, once per distinct query in the run, and execute it
+/// unless it is estimated to read too many rows.
+async fn run_query(
+    executor: &dyn Executor,
+    dashboard: &Dashboard,
+    panel: &Panel,
+    query: Query<'_>,
+    flags: &ExecuteFlags,
+    estimates: &mut HashMap<String, Estimate>,
+    progress: &Progress<'_>,
+) -> anyhow::Result<Outcome> {
+    let query_estimate = if flags.estimate || flags.max_estimated_rows.is_some() {
+        let query_estimate = match estimates.get(query.sql) {
+            Some(query_estimate) => *query_estimate,
+            None => {
+                let query_estimate = clickhouse::estimate(executor, query)
+                    .await
+                    .context("Failed to estimate the query")?;
+                estimates.insert(query.sql.to_string(), query_estimate);
+                query_estimate
+            }
+        };
+        debug!(panel_id = panel.id, ?query_estimate);
+        if flags
+            .max_estimated_rows
+            .is_some_and(|max| query_estimate.rows > max)
+        {
+            warn!(
+                panel_id = panel.id,
+                rows = query_estimate.rows,
+                "Skipping query estimated to read too many rows"
+            );
+            return Ok(Outcome::Skipped);
+        }
+        Some(query_estimate)
+    } else {
+        None
+    };
+    let bytes = execute_query(executor, dashboard, panel, query, progress).await?;
+    Ok(Outcome::Executed(bytes, query_estimate))
+}
+
+/// Execute a panel query, recording it in the metrics, and return the size of the response.
+async fn execute_query(
+    executor: &dyn Executor,
+    dashboard: &Dashboard,
+    panel: &Panel,
+    query: Query<'_>,
+    progress: &Progress<'_>,
+) -> anyhow::Result<usize> {
+    let start = Instant::now();
+    let response = executor.query_discard(query).await;
+    if let Some(metrics) = progress.metrics {
+        let response = response.as_ref().ok();
+        metrics.record_query(&dashboard.title, panel, start.elapsed(), response);
+    }
+    Ok(response?.bytes)
+}
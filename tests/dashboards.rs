@@ -0,0 +1,691 @@
+// End-to-end tests of the command line interface, against the dashboards in `tests/fixtures`.
+#![cfg(feature = "cli")]
+use std::collections::BTreeSet;
+
+use ch_grafana_cache::grafana::Dashboard;
+
+mod support;
+use support::{fixture, MockClickhouse, MockGrafana, Reply};
+
+fn set<'a>(items: impl IntoIterator<Item = &'a str>) -> BTreeSet<String> {
+    items.into_iter().map(String::from).collect()
+}
+
+/// Variables queries of the `clickhouse` fixture, after substitution.
+const VARIABLES_SQL: [&str; 3] = [
+    "SELECT DISTINCT host FROM logs WHERE env = 'prod'",
+    "SELECT DISTINCT host FROM logs WHERE env = 'staging'",
+    "SELECT name AS __text, id AS __value FROM services",
+];
+/// Panel and annotation queries of the `clickhouse` fixture, for all the variables combinations.
+const PANELS_SQL: [&str; 16] = [
+    "SELECT count() FROM logs WHERE host = 'a' AND service_id = 1",
+    "SELECT count() FROM logs WHERE host = 'b' AND service_id = 1",
+    "SELECT count() FROM logs WHERE host = 'c' AND service_id = 1",
+    "SELECT message\nFROM errors\nWHERE env = 'prod' AND host = 'a'\nLIMIT 30",
+    "SELECT message\nFROM errors\nWHERE env = 'prod' AND host = 'b'\nLIMIT 30",
+    "SELECT message\nFROM errors\nWHERE env = 'staging' AND host = 'c'\nLIMIT 30",
+    "SELECT count() FROM errors WHERE env = 'prod'",
+    "SELECT count() FROM errors WHERE env = 'staging'",
+    "SELECT uniq(user) FROM sessions WHERE host = 'a'",
+    "SELECT uniq(user) FROM sessions WHERE host = 'b'",
+    "SELECT uniq(user) FROM sessions WHERE host = 'c'",
+    "SELECT quantile(0.99)(duration) FROM requests WHERE host = 'a'",
+    "SELECT quantile(0.99)(duration) FROM requests WHERE host = 'b'",
+    "SELECT quantile(0.99)(duration) FROM requests WHERE host = 'c'",
+    "SELECT ts AS time, version AS text FROM deployments WHERE env = 'prod'",
+    "SELECT ts AS time, version AS text FROM deployments WHERE env = 'staging'",
+];
+
+#[test]
+fn parse_fixtures() -> anyhow::Result<()> {
+    let dashboard: Dashboard = serde_json::from_value(fixture("clickhouse"))?;
+    // Rows are flattened, collapsed or not
+    assert_eq!(
+        dashboard.panels.iter().map(|p| p.id).collect::<Vec<_>>(),
+        vec![2, 3, 5, 6, 7, 8]
+    );
+    assert_eq!(dashboard.panels.iter().flat_map(|p| p.sql()).count(), 4);
+    assert_eq!(
+        dashboard
+            .variables()
+            .map(|v| v.r#type.as_str())
+            .collect::<Vec<_>>(),
+        vec![
+            "custom",
+            "query",
+            "query",
+            "constant",
+            "textbox",
+            "interval",
+            "datasource",
+            "adhoc"
+        ]
+    );
+    assert_eq!(
+        dashboard
+            .variables_sql()
+            .map(|v| v.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["host", "service"]
+    );
+    assert_eq!(dashboard.time.from, "now-24h");
+    // Only the enabled Clickhouse annotation
+    assert_eq!(
+        dashboard
+            .annotations
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>(),
+        vec!["Annotation 1 (Deployments)"]
+    );
+
+    // Altinity plugin: the panel queries use macros in a `query` field, which is not supported.
+    let dashboard: Dashboard = serde_json::from_value(fixture("vertamedia"))?;
+    assert_eq!(dashboard.panels.len(), 1);
+    assert_eq!(dashboard.panels[0].sql().count(), 0);
+    assert_eq!(
+        dashboard
+            .variables_sql()
+            .map(|v| v.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["region"]
+    );
+
+    // Schema version 14, with rows
+    let dashboard: Dashboard = serde_json::from_value(fixture("legacy_rows"))?;
+    assert_eq!(
+        dashboard.panels.iter().map(|p| p.id).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert!(dashboard.panels.iter().all(|p| p.datasource.is_some()));
+    Ok(())
+}
+
+#[test]
+fn dashboard_changes() -> anyhow::Result<()> {
+    let previous: Dashboard = serde_json::from_value(fixture("clickhouse"))?;
+    // Dashboard with the given replacement in its JSON model
+    let edited = |from: &str, to: &str| -> Dashboard {
+        let json = fixture("clickhouse").to_string();
+        assert!(json.contains(from), "{}", from);
+        serde_json::from_str(&json.replace(from, to)).unwrap()
+    };
+    let changed = |dashboard: Dashboard| {
+        dashboard
+            .changes(&previous)
+            .queried_panels()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert!(changed(edited("", "")).is_empty());
+    // Panel query
+    assert_eq!(
+        changed(edited("FROM sessions", "FROM sessions_v2")),
+        vec!["Panel 5 (Users)"]
+    );
+    // Data source of a target
+    assert_eq!(
+        changed(edited(r#""uid":"ch-replica""#, r#""uid":"ch""#)),
+        vec!["Panel 3 (Errors)"]
+    );
+    // Variable used by a panel
+    assert_eq!(
+        changed(edited("FROM services", "FROM services_v2")),
+        vec!["Panel 2 (Requests)"]
+    );
+    // Variable used by a panel and another variable
+    assert_eq!(
+        changed(edited(r#""query":"prod,staging""#, r#""query":"prod""#)),
+        vec![
+            "Panel 2 (Requests)",
+            "Panel 3 (Errors)",
+            "Panel 5 (Users)",
+            "Annotation 1 (Deployments)"
+        ]
+    );
+    // New annotation
+    assert_eq!(
+        changed(edited(r#""enable":false"#, r#""enable":true"#)),
+        vec!["Annotation 2 (Incidents)"]
+    );
+    Ok(())
+}
+
+fn fixture_path(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// SQL queries in the output of `print`.
+fn printed_sql(stdout: &str) -> BTreeSet<String> {
+    stdout
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|block| !block.is_empty() && !block.ends_with(':'))
+        .map(|block| match block.split_once('\n') {
+            // Variable name or panel header
+            Some((header, sql)) if !header.starts_with("SELECT") => sql.to_string(),
+            _ => block.to_string(),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn print() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let output = support::run(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "print",
+    ])
+    .await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(
+        output
+            .stdout
+            .lines()
+            .filter(|l| l.starts_with("Panel ") || l.starts_with("Annotation "))
+            .collect::<Vec<_>>(),
+        vec![
+            "Panel 2 (Requests)",
+            "Panel 3 (Errors)",
+            "Panel 5 (Users)",
+            "Panel 7 (Latency (p99))",
+            "Annotation 1 (Deployments)"
+        ]
+    );
+    let clickhouse_sql = set([
+        "SELECT DISTINCT host FROM logs WHERE env = '${env}'",
+        "SELECT name AS __text, id AS __value FROM services",
+        "SELECT count() FROM logs WHERE host = '${host}' AND service_id = ${service}",
+        "SELECT message\nFROM errors\nWHERE env = '${env}' AND host = '${host}'\nLIMIT ${retention}",
+        "SELECT count() FROM errors WHERE env = '${env}'",
+        "SELECT uniq(user) FROM sessions WHERE host = '${host}'",
+        "SELECT quantile(0.99)(duration) FROM requests WHERE host = '${host}'",
+        "SELECT ts AS time, version AS text FROM deployments WHERE env = '${env}'",
+    ]);
+    assert_eq!(printed_sql(&output.stdout), clickhouse_sql);
+    assert!(grafana
+        .requests()
+        .iter()
+        .any(|r| r.path == "/api/library-elements/lib-latency"));
+
+    // Offline, with exported library panels
+    let output = support::run(&[
+        "--json",
+        &fixture_path("clickhouse.json"),
+        "--library-panels",
+        &fixture_path("library_panels"),
+        "print",
+    ])
+    .await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(printed_sql(&output.stdout), clickhouse_sql);
+
+    // Offline
+    let output = support::run(&["--json", &fixture_path("legacy_rows.json"), "print"]).await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(
+        printed_sql(&output.stdout),
+        set([
+            "SELECT count() FROM logs WHERE env = '${env}'",
+            "SELECT max(ts) FROM logs WHERE env = '${env}'",
+        ])
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_dashboard() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let args = |uid| {
+        [
+            "--grafana-url",
+            grafana.url.as_str(),
+            "--grafana-token",
+            "secret",
+            "--dashboard",
+            uid,
+            "print",
+        ]
+    };
+    let output = support::run(&args("logs")).await;
+    assert!(output.success, "{}", output.stderr);
+    let requests = grafana.requests();
+    assert_eq!(requests[0].path, "/api/dashboards/uid/logs");
+    assert_eq!(requests[0].header("authorization"), Some("Bearer secret"));
+
+    let output = support::run(&args("missing")).await;
+    assert!(!output.success);
+    assert!(output.stderr.contains("404"), "{}", output.stderr);
+    Ok(())
+}
+
+/// Query of the annotation disabled in the `clickhouse` fixture
+const INCIDENTS_SQL: &str = "SELECT ts AS time, title AS text FROM incidents";
+
+/// Clickhouse server answering the queries of the `clickhouse` fixture.
+async fn mock_clickhouse() -> MockClickhouse {
+    mock_clickhouse_with(MockClickhouse::start().await)
+}
+/// Add the replies to the queries of the `clickhouse` fixture, after the existing ones.
+fn mock_clickhouse_with(clickhouse: MockClickhouse) -> MockClickhouse {
+    clickhouse
+        .reply(VARIABLES_SQL[0], Reply::table(&["host"], &[&["a"], &["b"]]))
+        .reply(VARIABLES_SQL[1], Reply::table(&["host"], &[&["c"]]))
+        .reply(
+            VARIABLES_SQL[2],
+            Reply::table(&["__text", "__value"], &[&["api", "1"]]),
+        );
+    for sql in PANELS_SQL.into_iter().chain([INCIDENTS_SQL]) {
+        clickhouse.reply(sql, Reply::table(&["c"], &[&["1"]]));
+    }
+    clickhouse
+}
+
+#[tokio::test]
+async fn execute() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let clickhouse = mock_clickhouse().await;
+    let textfile =
+        std::env::temp_dir().join(format!("ch-grafana-cache-{}.prom", std::process::id()));
+    let output = support::run(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--url",
+        clickhouse.url.as_str(),
+        "--username",
+        "default",
+        "--metrics-textfile",
+        textfile.to_str().unwrap(),
+    ])
+    .await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(
+        clickhouse.queries().into_iter().collect::<BTreeSet<_>>(),
+        set(VARIABLES_SQL.into_iter().chain(PANELS_SQL))
+    );
+    let metrics = std::fs::read_to_string(&textfile)?;
+    std::fs::remove_file(&textfile)?;
+    // 6 queries for each of the 6 combinations
+    assert!(metrics
+        .lines()
+        .any(|l| l == r#"ch_grafana_cache_queries_total{dashboard="Logs"} 36"#));
+    assert!(
+        metrics.contains(r#"ch_grafana_cache_last_success_timestamp_seconds{dashboard="Logs"}"#)
+    );
+
+    // The warmup result prevails over the metrics
+    let output = support::run(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--url",
+        clickhouse.url.as_str(),
+        "--username",
+        "default",
+        "--metrics-textfile",
+        "/nonexistent/ch-grafana-cache.prom",
+    ])
+    .await;
+    assert!(output.success, "{}", output.stderr);
+    assert!(output.stderr.contains("Failed to write the metrics"));
+    Ok(())
+}
+
+/// Wait until the condition holds, failing after 30 seconds.
+async fn eventually(condition: impl Fn() -> bool) {
+    let start = std::time::Instant::now();
+    while !condition() {
+        assert!(start.elapsed().as_secs() < 30, "Timed out");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn execute_resume() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let clickhouse = MockClickhouse::start().await;
+    // Fails once, in the last combination
+    let failing = "SELECT uniq(user) FROM sessions WHERE host = 'c'";
+    clickhouse.reply(
+        failing,
+        Reply::error(404, 60, "Table default.sessions does not exist").times(1),
+    );
+    let clickhouse = mock_clickhouse_with(clickhouse);
+    let checkpoint = std::env::temp_dir().join(format!(
+        "ch-grafana-cache-checkpoint-{}.json",
+        std::process::id()
+    ));
+    let args = [
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--url",
+        clickhouse.url.as_str(),
+        "--username",
+        "default",
+        "--checkpoint",
+        checkpoint.to_str().unwrap(),
+        "--resume",
+    ];
+    let output = support::run(&args).await;
+    assert!(!output.success);
+    assert!(checkpoint.exists());
+    let executed: BTreeSet<String> = clickhouse
+        .queries()
+        .into_iter()
+        .filter(|q| q != failing && !VARIABLES_SQL.contains(&q.as_str()))
+        .collect();
+    let n_queries = clickhouse.queries().len();
+
+    let output = support::run(&args).await;
+    assert!(output.success, "{}", output.stderr);
+    assert!(!checkpoint.exists());
+    // The queries of the completed combinations are not executed again
+    let resumed: BTreeSet<String> = clickhouse.queries()[n_queries..]
+        .iter()
+        .filter(|q| !VARIABLES_SQL.contains(&q.as_str()))
+        .cloned()
+        .collect();
+    assert!(resumed.contains(failing));
+    assert!(!resumed.iter().any(|q| q.contains("host = 'a'")));
+    // Nor the ones executed before the failure, within its combination
+    assert!(executed.iter().any(|q| q.contains("host = 'c'")));
+    assert!(resumed.is_disjoint(&executed), "{:?}", resumed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn variables_cache() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let clickhouse = mock_clickhouse().await;
+    let cache = std::env::temp_dir().join(format!(
+        "ch-grafana-cache-variables-{}.json",
+        std::process::id()
+    ));
+    let grafana_args = ["--grafana-url", grafana.url.as_str(), "--dashboard", "logs"];
+    let cache_args = ["--variables-cache", cache.to_str().unwrap()];
+    let execute = || {
+        let args: Vec<&str> = grafana_args
+            .into_iter()
+            .chain(["execute", "--url", clickhouse.url.as_str()])
+            .chain(["--username", "default"])
+            .chain(cache_args)
+            .collect();
+        async move { support::run(&args).await }
+    };
+    let variables_tsv = || {
+        clickhouse
+            .requests()
+            .iter()
+            .filter(|r| r.param("default_format") == Some("TSVWithNames"))
+            .count()
+    };
+    let output = execute().await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(variables_tsv(), VARIABLES_SQL.len());
+    assert!(cache.exists());
+
+    // The variables values are read from the cache, and only executed again for the cache
+    let output = execute().await;
+    assert!(output.success, "{}", output.stderr);
+    assert_eq!(variables_tsv(), VARIABLES_SQL.len());
+
+    // Offline, the dashboard only has a saved value for `host`
+    let planned = cache.with_extension("jsonl");
+    let plan = |cache: bool| {
+        let args: Vec<&str> = grafana_args
+            .into_iter()
+            .chain(["plan", "--output", planned.to_str().unwrap()])
+            .chain(if cache { &cache_args[..] } else { &[] }.iter().copied())
+            .collect();
+        async move { support::run(&args).await }
+    };
+    let output = plan(false).await;
+    assert!(output.success, "{}", output.stderr);
+    assert!(!std::fs::read_to_string(&planned)?.contains("host = 'c'"));
+    let output = plan(true).await;
+    assert!(output.success, "{}", output.stderr);
+    assert!(std::fs::read_to_string(&planned)?.contains("host = 'c'"));
+
+    std::fs::remove_file(&cache)?;
+    std::fs::remove_file(&planned)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn serve() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let clickhouse = mock_clickhouse().await;
+    let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let _daemon = support::spawn(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "serve",
+        "--url",
+        clickhouse.url.as_str(),
+        "--username",
+        "default",
+        "--interval",
+        "1s",
+        "--metrics-listen",
+        &metrics_addr.to_string(),
+    ]);
+    eventually(|| {
+        let queries = clickhouse.queries();
+        PANELS_SQL
+            .iter()
+            .all(|sql| queries.iter().any(|q| q == sql))
+    })
+    .await;
+
+    // The dashboard is retrieved again before each run
+    let mut dashboard = fixture("clickhouse");
+    dashboard["annotations"]["list"][2]["enable"] = true.into();
+    grafana.dashboard("logs", dashboard);
+    eventually(|| clickhouse.queries().iter().any(|q| q == INCIDENTS_SQL)).await;
+
+    // The variables values are resolved again for each run, e.g. for new hosts
+    let variable_requests = clickhouse
+        .requests()
+        .into_iter()
+        .filter(|r| r.body == VARIABLES_SQL[2])
+        .collect::<Vec<_>>();
+    assert!(
+        variable_requests
+            .iter()
+            .filter(|r| r.param("default_format") == Some("TSVWithNames"))
+            .count()
+            >= 2
+    );
+
+    let metrics = reqwest::get(format!("http://{}/metrics", metrics_addr))
+        .await?
+        .text()
+        .await?;
+    assert!(
+        metrics.contains(r#"ch_grafana_cache_query_duration_seconds_count{dashboard="Logs",kind="annotation",panel="1"}"#),
+        "{}",
+        metrics
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn serve_watch() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"));
+    let clickhouse = mock_clickhouse().await;
+    let textfile = std::env::temp_dir().join(format!(
+        "ch-grafana-cache-watch-{}.prom",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&textfile);
+    let _daemon = support::spawn(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "serve",
+        "--url",
+        clickhouse.url.as_str(),
+        "--username",
+        "default",
+        "--interval",
+        "1h",
+        "--watch",
+        "200ms",
+        "--metrics-textfile",
+        textfile.to_str().unwrap(),
+    ]);
+    // The metrics are written at the end of each warmup
+    let read_metrics = || std::fs::read_to_string(&textfile).ok();
+    let last_success = |metrics: &str| {
+        metrics
+            .lines()
+            .find(|l| l.starts_with("ch_grafana_cache_last_success_timestamp_seconds"))
+            .map(str::to_owned)
+    };
+    eventually(|| read_metrics().is_some()).await;
+    let metrics = read_metrics().unwrap();
+    assert!(last_success(&metrics).is_some(), "{}", metrics);
+    let n_queries = clickhouse.queries().len();
+
+    let mut dashboard = fixture("clickhouse");
+    dashboard["version"] = 2.into();
+    dashboard["annotations"]["list"][2]["enable"] = true.into();
+    grafana.dashboard("logs", dashboard);
+    eventually(|| clickhouse.queries().iter().any(|q| q == INCIDENTS_SQL)).await;
+    // Only the new query is executed, along with the variables ones
+    let queries = clickhouse.queries();
+    assert!(queries[n_queries..]
+        .iter()
+        .all(|q| q == INCIDENTS_SQL || VARIABLES_SQL.contains(&q.as_str())));
+    // The partial warmup of the changes is not a success of the whole dashboard
+    eventually(|| read_metrics().is_some_and(|m| m != metrics)).await;
+    assert_eq!(
+        last_success(&read_metrics().unwrap()),
+        last_success(&metrics)
+    );
+    std::fs::remove_file(&textfile)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn execute_grafana_backend() -> anyhow::Result<()> {
+    let grafana = MockGrafana::start().await;
+    grafana
+        .dashboard("logs", fixture("clickhouse"))
+        .library_panel(fixture("library_panels/latency"))
+        .table(VARIABLES_SQL[0], &["host"], &[&["a"], &["b"]])
+        .table(VARIABLES_SQL[1], &["host"], &[&["c"]])
+        .table(VARIABLES_SQL[2], &["__text", "__value"], &[&["api", "1"]]);
+    let output = support::run(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--backend",
+        "grafana",
+    ])
+    .await;
+    assert!(output.success, "{}", output.stderr);
+    let queries = grafana.queries();
+    assert_eq!(
+        queries
+            .iter()
+            .map(|q| q["rawSql"].as_str().unwrap())
+            .collect::<BTreeSet<_>>(),
+        VARIABLES_SQL
+            .into_iter()
+            .chain(PANELS_SQL)
+            .collect::<BTreeSet<_>>()
+    );
+    // Data source of the target, rather than of the panel
+    assert!(queries.iter().all(|q| {
+        let replica = q["rawSql"]
+            .as_str()
+            .unwrap()
+            .starts_with("SELECT count() FROM errors");
+        q["datasource"]["uid"] == if replica { "ch-replica" } else { "ch" }
+    }));
+
+    // The query log is only readable with a Clickhouse client
+    let output = support::run(&[
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--backend",
+        "grafana",
+        "--mode",
+        "popular",
+    ])
+    .await;
+    assert!(!output.success);
+    assert!(
+        output.stderr.contains("--mode popular requires --url"),
+        "{}",
+        output.stderr
+    );
+
+    // The throttling only applies to the requests sent to Clickhouse directly
+    let args = [
+        "--grafana-url",
+        grafana.url.as_str(),
+        "--dashboard",
+        "logs",
+        "execute",
+        "--backend",
+        "grafana",
+        "--max-qps",
+        "5",
+    ];
+    let output = support::run(&args).await;
+    assert!(!output.success);
+    assert!(output.stderr.contains("--url"), "{}", output.stderr);
+    let output = support::run(&[&args[..], &["--url", "http://localhost:8123"]].concat()).await;
+    assert!(!output.success);
+    assert!(
+        output
+            .stderr
+            .contains("only apply to the clickhouse backend"),
+        "{}",
+        output.stderr
+    );
+    Ok(())
+}
@@ -0,0 +1,277 @@
+//! Local stand-in for the Clickhouse HTTP interface, answering scripted queries.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+
+use ch_grafana_cache::clickhouse;
+
+use super::Request;
+
+/// Scripted answer to a query.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    status: StatusCode,
+    payload: Payload,
+    headers: Vec<(String, String)>,
+    delay: Option<Duration>,
+    /// Number of times the reply can be used, unlimited if `None`
+    times: Option<usize>,
+}
+#[derive(Clone, Debug)]
+enum Payload {
+    /// Rows, encoded according to the requested `default_format`
+    Table {
+        names: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    Raw(Vec<u8>),
+}
+impl Reply {
+    /// Rows of `String` columns, sent as TSVWithNames or Native depending on the request.
+    pub fn table(names: &[&str], rows: &[&[&str]]) -> Self {
+        Self::new(
+            StatusCode::OK,
+            Payload::Table {
+                names: names.iter().map(|n| n.to_string()).collect(),
+                rows: rows
+                    .iter()
+                    .map(|r| r.iter().map(|c| c.to_string()).collect())
+                    .collect(),
+            },
+        )
+    }
+    /// Payload sent as-is, regardless of the requested format.
+    pub fn raw(payload: impl Into<Vec<u8>>) -> Self {
+        Self::new(StatusCode::OK, Payload::Raw(payload.into()))
+    }
+    /// Clickhouse exception, with the given HTTP status and error code.
+    pub fn error(status: u16, code: u32, message: &str) -> Self {
+        Self::new(
+            StatusCode::from_u16(status).unwrap(),
+            Payload::Raw(format!("Code: {}. DB::Exception: {}. (UNKNOWN)\n", code, message).into()),
+        )
+        .header("X-ClickHouse-Exception-Code", &code.to_string())
+    }
+    fn new(status: StatusCode, payload: Payload) -> Self {
+        Self {
+            status,
+            payload,
+            headers: vec![],
+            delay: None,
+            times: None,
+        }
+    }
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    /// Wait before answering
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+    /// Only use the reply for the next `times` matching queries
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+    fn encode(&self, format: &str) -> Vec<u8> {
+        match (&self.payload, format) {
+            (Payload::Raw(payload), _) => payload.clone(),
+            (Payload::Table { names, rows }, "Native") => encode_native(names, rows),
+            (Payload::Table { names, rows }, _) => std::iter::once(names)
+                .chain(rows)
+                .map(|r| format!("{}\n", r.join("\t")))
+                .collect::<String>()
+                .into_bytes(),
+        }
+    }
+}
+
+/// Encode `String` columns in the Native format.
+///
+/// See <https://clickhouse.com/docs/en/interfaces/formats#native>
+fn encode_native(names: &[String], rows: &[Vec<String>]) -> Vec<u8> {
+    fn varint(out: &mut Vec<u8>, mut n: usize) {
+        while n >= 0x80 {
+            out.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+    fn string(out: &mut Vec<u8>, s: &str) {
+        varint(out, s.len());
+        out.extend(s.as_bytes());
+    }
+    let mut out = vec![];
+    if rows.is_empty() {
+        return out;
+    }
+    varint(&mut out, names.len());
+    varint(&mut out, rows.len());
+    for (i, name) in names.iter().enumerate() {
+        string(&mut out, name);
+        string(&mut out, "String");
+        for row in rows {
+            string(&mut out, &row[i]);
+        }
+    }
+    out
+}
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+}
+impl Pattern {
+    fn matches(&self, query: &str) -> bool {
+        match self {
+            Pattern::Exact(q) => q == query,
+            Pattern::Prefix(prefix) => query.starts_with(prefix),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Replies per query, matched on the trimmed body
+    replies: Vec<(Pattern, Reply)>,
+    requests: Vec<Request>,
+}
+
+/// Mock Clickhouse server, listening on a random local port until dropped.
+pub struct MockClickhouse {
+    pub url: reqwest::Url,
+    state: Arc<Mutex<MockState>>,
+    server: tokio::task::JoinHandle<()>,
+}
+impl Drop for MockClickhouse {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+impl MockClickhouse {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let app = axum::Router::new()
+            .route("/", axum::routing::post(handle))
+            .with_state(state.clone());
+        let (url, server) = super::serve(app).await;
+        Self { url, state, server }
+    }
+    /// Answer the given query (compared after trimming) with the reply.
+    ///
+    /// Replies are used in the order they were registered, until exhausted (see
+    /// [`Reply::times`]). Unknown queries are answered with an error.
+    pub fn reply(&self, query: &str, reply: Reply) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state
+            .replies
+            .push((Pattern::Exact(query.trim().into()), reply));
+        self
+    }
+    /// Answer the queries starting with the given prefix, e.g. with generated parts, see
+    /// [`Self::reply`].
+    pub fn reply_prefix(&self, prefix: &str, reply: Reply) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state
+            .replies
+            .push((Pattern::Prefix(prefix.trim().into()), reply));
+        self
+    }
+    /// Requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+    /// Bodies of the requests received so far
+    pub fn queries(&self) -> Vec<String> {
+        self.requests().into_iter().map(|r| r.body).collect()
+    }
+    /// Options of a client connecting to the server as the default user.
+    pub fn flags(&self) -> clickhouse::Flags {
+        clickhouse::Flags {
+            url: self.url.clone(),
+            auth: clickhouse::AuthFlags {
+                username: Some("default".into()),
+                ..Default::default()
+            },
+            settings: Default::default(),
+            profile: Default::default(),
+            tls: Default::default(),
+            throttle: Default::default(),
+            query_timeout: None,
+        }
+    }
+    /// Client connecting to the server, with the given settings and profile.
+    pub fn client(
+        &self,
+        settings: clickhouse::SettingsFlags,
+        profile: clickhouse::ProfileFlags,
+    ) -> clickhouse::ChClient {
+        clickhouse::ChClient::from_flags(&clickhouse::Flags {
+            settings,
+            profile,
+            ..self.flags()
+        })
+        .unwrap()
+    }
+}
+
+async fn handle(
+    State(state): State<Arc<Mutex<MockState>>>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+    body: String,
+) -> axum::response::Response {
+    let request = Request {
+        path: "/".into(),
+        body,
+        params,
+        headers,
+    };
+    let reply = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        let query = request.body.trim();
+        state
+            .replies
+            .iter_mut()
+            .find(|(q, r)| q.matches(query) && r.times != Some(0))
+            .map(|(_, reply)| {
+                if let Some(times) = &mut reply.times {
+                    *times -= 1;
+                }
+                reply.clone()
+            })
+    };
+    let Some(reply) = reply else {
+        return Reply::error(500, 62, &format!("Unexpected query {}", request.body))
+            .into_response(&request);
+    };
+    if let Some(delay) = reply.delay {
+        tokio::time::sleep(delay).await;
+    }
+    reply.into_response(&request)
+}
+
+impl Reply {
+    fn into_response(self, request: &Request) -> axum::response::Response {
+        let format = request.param("default_format").unwrap_or("TabSeparated");
+        let mut headers: Vec<(String, String)> = vec![(
+            "X-ClickHouse-Query-Id".into(),
+            request.param("query_id").unwrap_or("mock").into(),
+        )];
+        headers.extend(self.headers.iter().cloned());
+        let mut resp = (self.status, self.encode(format)).into_response();
+        for (name, value) in headers {
+            resp.headers_mut().insert(
+                axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        resp
+    }
+}
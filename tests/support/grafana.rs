@@ -0,0 +1,197 @@
+//! Local stand-in for the Grafana HTTP API, serving dashboards and library panels, and executing
+//! data source queries from scripted tables.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_json::{json, Value};
+
+use super::Request;
+
+#[derive(Default)]
+struct MockState {
+    dashboards: HashMap<String, Value>,
+    library_panels: HashMap<String, Value>,
+    /// Rows (column names first) per SQL query, compared after trimming
+    tables: HashMap<String, Vec<Vec<String>>>,
+    /// Status and error message per SQL query
+    errors: HashMap<String, (StatusCode, String)>,
+    requests: Vec<Request>,
+}
+type SharedState = Arc<Mutex<MockState>>;
+
+/// Mock Grafana server, listening on a random local port until dropped.
+pub struct MockGrafana {
+    pub url: reqwest::Url,
+    state: SharedState,
+    server: tokio::task::JoinHandle<()>,
+}
+impl Drop for MockGrafana {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+impl MockGrafana {
+    pub async fn start() -> Self {
+        let state = SharedState::default();
+        let app = axum::Router::new()
+            .route("/api/dashboards/uid/:uid", axum::routing::get(dashboard))
+            .route(
+                "/api/library-elements/:uid",
+                axum::routing::get(library_panel),
+            )
+            .route("/api/ds/query", axum::routing::post(ds_query))
+            .with_state(state.clone());
+        let (url, server) = super::serve(app).await;
+        Self { url, state, server }
+    }
+    /// Serve the dashboard JSON model under the given uid.
+    pub fn dashboard(&self, uid: &str, dashboard: Value) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.dashboards.insert(uid.into(), dashboard);
+        self
+    }
+    /// Serve the library panel, given as returned by the API (`{"result": { "uid": ...}}`).
+    pub fn library_panel(&self, element: Value) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        let uid = element["result"]["uid"].as_str().unwrap().to_string();
+        state.library_panels.insert(uid, element);
+        self
+    }
+    /// Answer the given query with a single data frame. Other queries return no frames.
+    pub fn table(&self, query: &str, names: &[&str], rows: &[&[&str]]) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.tables.insert(
+            query.trim().into(),
+            std::iter::once(names)
+                .chain(rows.iter().copied())
+                .map(|r| r.iter().map(|c| c.to_string()).collect())
+                .collect(),
+        );
+        self
+    }
+    /// Fail the given query with the error, as reported by the data source.
+    pub fn error(&self, query: &str, status: u16, message: &str) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.errors.insert(
+            query.trim().into(),
+            (StatusCode::from_u16(status).unwrap(), message.into()),
+        );
+        self
+    }
+    /// Requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+    /// Query models posted to `/api/ds/query` so far
+    pub fn queries(&self) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == "/api/ds/query")
+            .flat_map(|r| {
+                let body: Value = serde_json::from_str(&r.body).unwrap();
+                body["queries"].as_array().unwrap().clone()
+            })
+            .collect()
+    }
+}
+
+fn record(state: &SharedState, path: String, headers: HeaderMap, body: String) {
+    state.lock().unwrap().requests.push(Request {
+        path,
+        body,
+        params: vec![],
+        headers,
+    });
+}
+
+async fn dashboard(
+    State(state): State<SharedState>,
+    Path(uid): Path<String>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    record(
+        &state,
+        format!("/api/dashboards/uid/{}", uid),
+        headers,
+        String::new(),
+    );
+    let state = state.lock().unwrap();
+    match state.dashboards.get(&uid) {
+        Some(dashboard) => Json(json!({
+            "dashboard": dashboard,
+            "meta": { "slug": uid, "version": dashboard["version"] }
+        }))
+        .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": "Dashboard not found" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn library_panel(
+    State(state): State<SharedState>,
+    Path(uid): Path<String>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    record(
+        &state,
+        format!("/api/library-elements/{}", uid),
+        headers,
+        String::new(),
+    );
+    let state = state.lock().unwrap();
+    match state.library_panels.get(&uid) {
+        Some(element) => Json(element.clone()).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": "library element could not be found" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn ds_query(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    body: String,
+) -> axum::response::Response {
+    record(&state, "/api/ds/query".into(), headers, body.clone());
+    let Ok(body) = serde_json::from_str::<Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, "Invalid body").into_response();
+    };
+    let state = state.lock().unwrap();
+    let mut results = serde_json::Map::new();
+    for query in body["queries"].as_array().into_iter().flatten() {
+        let ref_id = query["refId"].as_str().unwrap_or("A");
+        let sql = query["rawSql"].as_str().unwrap_or_default().trim();
+        // Grafana answers with the status of the failed query
+        if let Some((status, error)) = state.errors.get(sql) {
+            let result = json!({ "status": status.as_u16(), "error": error });
+            return (*status, Json(json!({ "results": { ref_id: result } }))).into_response();
+        }
+        let frames = match state.tables.get(sql) {
+            Some(table) => {
+                let (names, rows) = table.split_first().unwrap();
+                let values: Vec<Vec<&String>> = (0..names.len())
+                    .map(|i| rows.iter().map(|r| &r[i]).collect())
+                    .collect();
+                json!([{
+                    "schema": {
+                        "refId": ref_id,
+                        "fields": names.iter().map(|n| json!({ "name": n, "type": "string" })).collect::<Vec<_>>()
+                    },
+                    "data": { "values": values }
+                }])
+            }
+            None => json!([]),
+        };
+        results.insert(ref_id.into(), json!({ "status": 200, "frames": frames }));
+    }
+    Json(json!({ "results": results })).into_response()
+}
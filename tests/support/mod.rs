@@ -0,0 +1,133 @@
+//! Local stand-ins for the Clickhouse and Grafana HTTP APIs, and dashboard fixtures.
+#![allow(dead_code, unused_imports)]
+use axum::http::HeaderMap;
+
+use ch_grafana_cache::grafana::Dashboard;
+
+mod clickhouse;
+mod grafana;
+pub use clickhouse::{MockClickhouse, Reply};
+pub use grafana::MockGrafana;
+
+/// Request received by a mock server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub path: String,
+    pub body: String,
+    pub params: Vec<(String, String)>,
+    pub headers: HeaderMap,
+}
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|h| h.to_str().ok())
+    }
+}
+
+/// Serve the router on a random local port, returning the base URL.
+async fn serve(app: axum::Router) -> (reqwest::Url, tokio::task::JoinHandle<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, server)
+}
+
+/// Dashboard JSON from `tests/fixtures`.
+pub fn fixture(name: &str) -> serde_json::Value {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("{}.json", name));
+    serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+}
+
+/// Dashboard with a panel of the given queries on the `logs` table, and a `host` variable.
+pub fn logs_dashboard(queries: &[&str]) -> Dashboard {
+    let targets: Vec<_> = queries
+        .iter()
+        .zip('A'..)
+        .map(|(sql, ref_id)| serde_json::json!({ "refId": ref_id.to_string(), "rawSql": sql }))
+        .collect();
+    serde_json::from_value(serde_json::json!({
+        "title": "test",
+        "panels": [{
+            "id": 1,
+            "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" },
+            "targets": targets
+        }],
+        "templating": { "list": [{
+            "name": "host",
+            "query": "SELECT DISTINCT host FROM logs",
+            "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" }
+        }]}
+    }))
+    .unwrap()
+}
+
+/// Output of the command line interface.
+#[cfg(feature = "cli")]
+pub struct Output {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Command line interface, without the environment variables it reads.
+#[cfg(feature = "cli")]
+fn command(args: &[&str]) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_ch-grafana-cache"));
+    command
+        .args(args)
+        .env_remove("GRAFANA_URL")
+        .env_remove("GRAFANA_TOKEN")
+        .env_remove("GRAFANA_CA_CERT")
+        .env_remove("GRAFANA_CLIENT_CERT")
+        .env_remove("GRAFANA_CLIENT_KEY")
+        .env_remove("CLICKHOUSE_URL")
+        .env_remove("CLICKHOUSE_AUTH")
+        .env_remove("CLICKHOUSE_USERNAME")
+        .env_remove("CLICKHOUSE_PASSWORD")
+        .env_remove("CLICKHOUSE_PASSWORD_FILE")
+        .env_remove("CLICKHOUSE_TOKEN")
+        .env_remove("CLICKHOUSE_TOKEN_FILE")
+        .env_remove("CLICKHOUSE_CA_CERT")
+        .env_remove("CLICKHOUSE_CLIENT_CERT")
+        .env_remove("CLICKHOUSE_CLIENT_KEY")
+        .env_remove("CLICKHOUSE_DATABASE");
+    command
+}
+
+/// Start the command line interface in the background, killing it when the handle is dropped.
+#[cfg(feature = "cli")]
+pub fn spawn(args: &[&str]) -> tokio::process::Child {
+    command(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+/// Run the command line interface with the given arguments, stripping the colors of the output.
+#[cfg(feature = "cli")]
+pub async fn run(args: &[&str]) -> Output {
+    let output = command(args).output().await.unwrap();
+    let ansi = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+    let text = |b: Vec<u8>| {
+        ansi.replace_all(&String::from_utf8(b).unwrap(), "")
+            .into_owned()
+    };
+    Output {
+        success: output.status.success(),
+        stdout: text(output.stdout),
+        stderr: text(output.stderr),
+    }
+}
@@ -1,12 +1,29 @@
+use std::time::Duration;
+
 use ch_grafana_cache::clickhouse;
+use ch_grafana_cache::grafana;
+use ch_grafana_cache::popularity;
+use ch_grafana_cache::verify;
+use ch_grafana_cache::warmup;
+
+mod support;
+use support::{MockClickhouse, MockGrafana, Reply};
 
 #[tokio::test]
+#[ignore = "requires a Clickhouse server on localhost:8123"]
 async fn clickhouse() -> anyhow::Result<()> {
     let ch = clickhouse::ChClient::from_flags(&clickhouse::Flags {
         url: "http://localhost:8123".parse()?,
-        username: "default".into(),
-        password: None,
-    });
+        auth: clickhouse::AuthFlags {
+            username: Some("default".into()),
+            ..Default::default()
+        },
+        settings: Default::default(),
+        profile: Default::default(),
+        tls: Default::default(),
+        throttle: Default::default(),
+        query_timeout: None,
+    })?;
 
     let bytes = ch
         .query_native("SELECT * from system.zeros LIMIT 0".into())
@@ -32,3 +49,791 @@ async fn clickhouse() -> anyhow::Result<()> {
     assert_eq!("0,1\n1,2\n2,3\n", r);
     Ok(())
 }
+
+#[tokio::test]
+async fn mock_clickhouse() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    let query = "SELECT number, number + 1 FROM system.numbers LIMIT 3";
+    server
+        .reply(
+            query,
+            Reply::table(&["a", "b"], &[&["0", "1"], &["1", "2"]]),
+        )
+        .reply("SELECT 1", Reply::raw("").header("X-Cache", "HIT"));
+    let settings = clickhouse::SettingsFlags {
+        all: vec![("use_query_cache".into(), "1".into())],
+        panels: vec![("query_cache_ttl".into(), "60".into())],
+        ..Default::default()
+    };
+    let ch = server.client(settings, Default::default());
+
+    // Native
+    assert_eq!(ch.query_native(query.into()).await?, 28);
+    let resp = ch.query_native_response("SELECT 1".into()).await?;
+    assert_eq!(resp.bytes, 0);
+    assert_eq!(resp.cache_hit, Some(true));
+    let query_id = resp.query_id.unwrap();
+    assert!(query_id.starts_with("ch-grafana-cache-"), "{}", query_id);
+    assert!(query_id.ends_with("-1"), "{}", query_id);
+
+    // TSV, cached
+    let table = ch
+        .for_queries(clickhouse::QueryKind::Panel)
+        .query_table(query.into(), true)
+        .await?;
+    assert_eq!(table.names, vec!["a", "b"]);
+    assert_eq!(table.rows[1].cols, vec!["1", "2"]);
+    ch.query_table(query.into(), true).await?;
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|r| r
+        .header("user-agent")
+        .unwrap()
+        .starts_with("ch-grafana-cache/")
+        && r.header("authorization").is_some()));
+    assert_eq!(
+        requests[0].params[..2],
+        [
+            ("default_format".to_string(), "Native".to_string()),
+            ("use_query_cache".into(), "1".into())
+        ]
+    );
+    assert_eq!(requests[0].params[2].0, "query_id");
+    assert_eq!(requests[2].param("default_format"), Some("TSVWithNames"));
+    assert_eq!(requests[2].param("query_cache_ttl"), Some("60"));
+
+    // Distinct from the ids of another process
+    let other = server.client(Default::default(), Default::default());
+    let other_id = other
+        .query_native_response("SELECT 1".into())
+        .await?
+        .query_id;
+    assert_ne!(other_id.unwrap(), query_id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_clickhouse_grafana_compat() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server.reply("SELECT 1", Reply::table(&["1"], &[&["1"]]));
+    let ch = server.client(
+        Default::default(),
+        clickhouse::ProfileFlags {
+            profile: clickhouse::Profile::GrafanaCompat,
+            compression: clickhouse::Compression::Deflate,
+            database: Some("logs".into()),
+        },
+    );
+    ch.query_native("SELECT 1".into()).await?;
+    let request = &server.requests()[0];
+    assert_eq!(
+        request
+            .params
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>(),
+        vec!["database", "default_format", "enable_http_compression",]
+    );
+    assert_eq!(request.param("database"), Some("logs"));
+    assert_eq!(request.header("accept-encoding"), Some("deflate"));
+    assert!(request.header("transfer-encoding").is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_clickhouse_auth() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server.reply("SELECT 1", Reply::table(&["1"], &[&["1"]]));
+    let secret =
+        std::env::temp_dir().join(format!("ch-grafana-cache-secret-{}", std::process::id()));
+    std::fs::write(&secret, "s3cret\n")?;
+    let query = |auth: clickhouse::AuthFlags| async {
+        let ch = clickhouse::ChClient::from_flags(&clickhouse::Flags {
+            auth,
+            ..server.flags()
+        })?;
+        ch.query("SELECT 1".into(), false).await?;
+        anyhow::Ok(server.requests().pop().unwrap())
+    };
+
+    let request = query(clickhouse::AuthFlags {
+        auth: clickhouse::Auth::Headers,
+        username: Some("warmup".into()),
+        password_file: Some(secret.clone()),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(request.header("x-clickhouse-user"), Some("warmup"));
+    assert_eq!(request.header("x-clickhouse-key"), Some("s3cret"));
+    assert!(request.header("authorization").is_none());
+
+    let request = query(clickhouse::AuthFlags {
+        auth: clickhouse::Auth::Bearer,
+        token_file: Some(secret.clone()),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(request.header("authorization"), Some("Bearer s3cret"));
+
+    let request = query(clickhouse::AuthFlags {
+        auth: clickhouse::Auth::None,
+        ..Default::default()
+    })
+    .await?;
+    assert!(request.header("authorization").is_none());
+    assert!(request.header("x-clickhouse-user").is_none());
+
+    // Missing credentials
+    for auth in [clickhouse::Auth::Basic, clickhouse::Auth::Bearer] {
+        let err = query(clickhouse::AuthFlags {
+            auth,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("requires"), "{}", err);
+    }
+    std::fs::remove_file(&secret)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_clickhouse_errors() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT * FROM missing",
+            Reply::error(404, 60, "Table default.missing does not exist"),
+        )
+        .reply("SELECT x", Reply::error(500, 47, "Missing columns: 'x'"))
+        .reply(
+            "SELECT 1",
+            Reply::error(503, 202, "Too many queries").times(1),
+        )
+        .reply(
+            "SELECT 1",
+            Reply::table(&["1"], &[&["1"]]).delay(Duration::from_millis(200)),
+        )
+        .reply(
+            "SELECT sleep(3)",
+            Reply::error(500, 159, "Timeout exceeded: elapsed 1 seconds"),
+        );
+    let ch = server.client(Default::default(), Default::default());
+
+    let err = ch
+        .query_native("SELECT * FROM missing".into())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("does not exist"));
+    // Not retried
+    assert_eq!(server.requests().len(), 1);
+    // Not retried either, despite the 500 status, as the error code is not transient
+    assert!(ch.query("SELECT x".into(), false).await.is_err());
+    assert_eq!(server.requests().len(), 2);
+
+    // Transient error, retried
+    let start = std::time::Instant::now();
+    assert_eq!(ch.query("SELECT 1".into(), false).await?.len(), 1);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    // Same id, replacing the previous attempt if still running
+    assert_eq!(requests[2].param("query_id"), requests[3].param("query_id"));
+    assert_eq!(requests[2].param("replace_running_query"), None);
+    assert_eq!(requests[3].param("replace_running_query"), Some("1"));
+
+    // Transient for --keep-going, but not retried right away as it would time out again
+    let err = ch.query("SELECT sleep(3)".into(), false).await.unwrap_err();
+    assert!(clickhouse::Error::is_transient_error(&err));
+    assert_eq!(server.requests().len(), 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_clickhouse_throttle() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT 1",
+            Reply::error(500, 241, "Memory limit exceeded").times(1),
+        )
+        .reply("SELECT 1", Reply::table(&["1"], &[&["1"]]));
+    let ch = clickhouse::ChClient::from_flags(&clickhouse::Flags {
+        throttle: ch_grafana_cache::throttle::ThrottleFlags {
+            max_qps: Some(20.0),
+            adaptive: true,
+            adaptive_latency: Duration::from_secs(10),
+        },
+        ..server.flags()
+    })?;
+
+    // Retried after the overload error, which also slows down the following requests
+    let start = std::time::Instant::now();
+    for _ in 0..4 {
+        ch.query("SELECT 1".into(), false).await?;
+    }
+    assert_eq!(server.queries().len(), 5);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_clickhouse_timeout() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT 1",
+            Reply::table(&["1"], &[&["1"]]).delay(Duration::from_secs(5)),
+        )
+        .reply_prefix(
+            "KILL QUERY",
+            Reply::error(503, 202, "Too many simultaneous queries"),
+        );
+    let ch = clickhouse::ChClient::from_flags(&clickhouse::Flags {
+        throttle: ch_grafana_cache::throttle::ThrottleFlags {
+            max_qps: Some(1.0),
+            ..Default::default()
+        },
+        query_timeout: Some(Duration::from_millis(200)),
+        ..server.flags()
+    })?;
+
+    let start = std::time::Instant::now();
+    let err = ch.query_native("SELECT 1".into()).await.unwrap_err();
+    // Killed without waiting for the throttle
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let Some(clickhouse::Error::QueryTimeout { query_id, .. }) = err.downcast_ref() else {
+        panic!("Unexpected error {:?}", err);
+    };
+    assert!(query_id.ends_with("-1"));
+    // Cancelled on the server, once despite the transient error
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].param("query_id"), Some(query_id.as_str()));
+    assert_eq!(
+        requests[1].body,
+        format!("KILL QUERY WHERE query_id = '{}'", query_id)
+    );
+    assert!(requests[1].header("authorization").is_some());
+
+    // Deterministic ids, numbering the executions
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let _ = ch.query_native("SELECT 1".into()).await;
+    let requests = server.requests();
+    assert_eq!(
+        requests[2].param("query_id").unwrap(),
+        format!("{}-2", query_id.strip_suffix("-1").unwrap())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_execute() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT DISTINCT host FROM logs",
+            Reply::table(&["host"], &[&["a"], &["b"]]),
+        )
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'a'",
+            Reply::table(&["c"], &[&["1"]]),
+        )
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'b'",
+            Reply::table(&["c"], &[&["2"]]),
+        );
+    let ch = server.client(Default::default(), Default::default());
+
+    let dashboard = support::logs_dashboard(&["SELECT count() FROM logs WHERE host = '${host}'"]);
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+    let budget = warmup::Budget::default();
+    let mut tracker = budget.start();
+    let summary = warmup::execute(
+        &dashboard,
+        combinations,
+        None,
+        &ch,
+        &Default::default(),
+        &mut tracker,
+        Default::default(),
+    )
+    .await?;
+    assert_eq!(summary.combinations, 2);
+    assert_eq!(summary.queries, 2);
+    assert!(summary.bytes > 0);
+    assert_eq!(
+        server.queries(),
+        vec![
+            // Variable query, once as TSV and once as Native
+            "SELECT DISTINCT host FROM logs",
+            "SELECT DISTINCT host FROM logs",
+            "SELECT count() FROM logs WHERE host = 'a'",
+            "SELECT count() FROM logs WHERE host = 'b'",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_execute_keep_going() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT DISTINCT host FROM logs",
+            Reply::table(&["host"], &[&["a"], &["b"], &["c"]]),
+        )
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'a'",
+            Reply::error(500, 62, "Syntax error (SYNTAX_ERROR)"),
+        )
+        // Failing the initial attempt and the 3 retries, and succeeding at the end of the run
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'b'",
+            Reply::error(500, 241, "Memory limit exceeded").times(4),
+        )
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'b'",
+            Reply::table(&["c"], &[&["2"]]),
+        )
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'c'",
+            Reply::table(&["c"], &[&["3"]]),
+        );
+    let ch = server.client(Default::default(), Default::default());
+
+    let dashboard = support::logs_dashboard(&["SELECT count() FROM logs WHERE host = '${host}'"]);
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let execute = |flags: warmup::ExecuteFlags| {
+        let (dashboard, config, selection, ch) = (&dashboard, &config, &selection, &ch);
+        async move {
+            let combinations = dashboard.variables_combinations(config, selection, Some(ch));
+            let mut tracker = warmup::Budget::default().start();
+            warmup::execute(
+                dashboard,
+                combinations,
+                None,
+                ch,
+                &flags,
+                &mut tracker,
+                Default::default(),
+            )
+            .await
+        }
+    };
+
+    // Stops at the first failure, which is a typed Clickhouse error
+    let err = execute(Default::default()).await.unwrap_err();
+    let ch_err = err.downcast_ref::<clickhouse::Error>().unwrap();
+    assert!(matches!(ch_err, clickhouse::Error::Query(e) if e.code == 62));
+    assert!(!ch_err.is_transient());
+
+    let summary = execute(warmup::ExecuteFlags {
+        keep_going: true,
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(summary.combinations, 3);
+    assert_eq!(summary.queries, 2);
+    assert_eq!(summary.failed, 1);
+    let queries = server.queries();
+    assert_eq!(
+        queries[queries.len() - 2..],
+        [
+            "SELECT count() FROM logs WHERE host = 'c'",
+            "SELECT count() FROM logs WHERE host = 'b'",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_verify_query_log() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply("SELECT count() FROM logs", Reply::table(&["c"], &[&["1"]]))
+        .reply("SYSTEM FLUSH LOGS", Reply::raw(""))
+        .reply_prefix(
+            "SELECT query_id, query_cache_usage FROM system.query_log",
+            Reply::table(&["query_id", "query_cache_usage"], &[]),
+        );
+    let settings = clickhouse::SettingsFlags {
+        all: vec![("use_query_cache".into(), "1".into())],
+        ..Default::default()
+    };
+    let ch = server.client(settings, Default::default());
+
+    let dashboard: grafana::Dashboard = serde_json::from_value(serde_json::json!({
+        "title": "test",
+        "panels": [{
+            "id": 1,
+            "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" },
+            "targets": [{ "refId": "A", "rawSql": "SELECT count() FROM logs" }]
+        }],
+        "templating": { "list": [] }
+    }))?;
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+    let mut tracker = warmup::Budget::default().start();
+    let reports = verify::verify(
+        &dashboard,
+        combinations,
+        &ch,
+        verify::Method::QueryLog,
+        &mut tracker,
+    )
+    .await?;
+    assert_eq!(reports.values().map(|r| r.unknown).sum::<usize>(), 1);
+
+    // The settings are only sent with the panel queries, as Clickhouse rejects the query cache
+    // on system tables
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    for request in &requests[..2] {
+        assert_eq!(request.param("use_query_cache"), Some("1"));
+    }
+    for request in &requests[2..] {
+        assert_eq!(request.param("use_query_cache"), None);
+    }
+    let query_id = requests[1].param("query_id").unwrap();
+    assert!(requests[3].body.contains(query_id), "{}", requests[3].body);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_popular() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT DISTINCT host FROM logs",
+            Reply::table(&["host"], &[&["a"], &["b"], &["c"]]),
+        )
+        .reply_prefix(
+            "SELECT query, count() AS c FROM system.query_log",
+            Reply::table(
+                &["query", "c"],
+                &[
+                    &["SELECT count() FROM logs WHERE host = 'b'", "5"],
+                    &["SELECT count() FROM logs WHERE host = 'a'", "2"],
+                    &["SELECT 1", "10"],
+                ],
+            ),
+        );
+    let settings = clickhouse::SettingsFlags {
+        all: vec![("use_query_cache".into(), "1".into())],
+        ..Default::default()
+    };
+    let ch = server.client(settings, Default::default());
+
+    let dashboard = support::logs_dashboard(&["SELECT count() FROM logs WHERE host = '${host}'"]);
+    let selection = grafana::Selection {
+        lookback: Duration::from_secs(3600),
+        ..grafana::Selection::new(grafana::Mode::Popular)
+    };
+    let config = grafana::VariablesConfig::default();
+    let popularity =
+        popularity::Popularity::from_query_log(&ch, &dashboard, selection.lookback).await?;
+    let rank = |limit, budget: warmup::Budget| {
+        let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+        let popularity = &popularity;
+        async move {
+            let ranked = popularity
+                .rank(combinations, limit, &budget.start())
+                .await?;
+            anyhow::Ok(
+                ranked
+                    .iter()
+                    .map(|c| c["host"].to_string())
+                    .collect::<Vec<_>>(),
+            )
+        }
+    };
+    // By decreasing popularity, without the combinations never observed
+    assert_eq!(rank(None, Default::default()).await?, vec!["b", "a"]);
+    assert_eq!(rank(Some(1), Default::default()).await?, vec!["b"]);
+    let budget = warmup::Budget {
+        time_budget: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    assert!(rank(None, budget).await?.is_empty());
+
+    let requests = server.requests();
+    let query_log = requests
+        .iter()
+        .find(|r| r.body.contains("system.query_log"))
+        .unwrap();
+    assert!(query_log.body.contains("INTERVAL 3600 SECOND"));
+    // Ignoring the queries of this tool, including with the user agent of Grafana
+    assert!(query_log
+        .body
+        .contains("NOT startsWith(query_id, 'ch-grafana-cache-')"));
+    assert!(!query_log.body.contains("http_user_agent"));
+    assert_eq!(query_log.param("use_query_cache"), None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_estimate() -> anyhow::Result<()> {
+    let server = MockClickhouse::start().await;
+    let estimate = |rows: &str| {
+        Reply::table(
+            &["database", "table", "parts", "rows", "marks"],
+            &[&["default", "logs", "1", rows, "1"]],
+        )
+    };
+    server
+        .reply(
+            "SELECT DISTINCT host FROM logs",
+            Reply::table(&["host"], &[&["a"], &["b"]]),
+        )
+        .reply(
+            "EXPLAIN ESTIMATE SELECT count() FROM logs WHERE host = 'a'",
+            estimate("10"),
+        )
+        .reply(
+            "EXPLAIN ESTIMATE SELECT count() FROM logs WHERE host = 'b'",
+            estimate("1000"),
+        )
+        .reply("EXPLAIN ESTIMATE SELECT max(ts) FROM logs", estimate("5"))
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'a'",
+            Reply::table(&["c"], &[&["1"]]),
+        )
+        .reply("SELECT max(ts) FROM logs", Reply::table(&["t"], &[&["1"]]));
+    let ch = server.client(Default::default(), Default::default());
+
+    let dashboard = support::logs_dashboard(&[
+        "SELECT count() FROM logs WHERE host = '${host}'",
+        "SELECT max(ts) FROM logs",
+    ]);
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let flags = warmup::ExecuteFlags {
+        max_estimated_rows: Some(100),
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+        let mut tracker = warmup::Budget::default().start();
+        let summary = warmup::execute(
+            &dashboard,
+            combinations,
+            None,
+            &ch,
+            &flags,
+            &mut tracker,
+            Default::default(),
+        )
+        .await?;
+        assert_eq!(summary.queries, 3);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.estimate.rows, 10 + 5 + 5);
+    }
+    // Estimated once per distinct query in each run, and again on the second run
+    let explains = server
+        .queries()
+        .into_iter()
+        .filter(|q| q.starts_with("EXPLAIN"))
+        .count();
+    assert_eq!(explains, 6);
+
+    let n_queries = server.queries().len();
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+    let err = warmup::execute(
+        &dashboard,
+        combinations,
+        None,
+        &ch,
+        &warmup::ExecuteFlags {
+            backend: warmup::BackendKind::Grafana,
+            ..flags
+        },
+        &mut warmup::Budget::default().start(),
+        Default::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("requires the clickhouse backend"));
+    // Before sending any query
+    assert_eq!(server.queries().len(), n_queries);
+
+    // The failed estimates are handled as failed queries with --keep-going
+    let server = MockClickhouse::start().await;
+    server
+        .reply(
+            "SELECT DISTINCT host FROM logs",
+            Reply::table(&["host"], &[&["a"], &["b"]]),
+        )
+        .reply(
+            "EXPLAIN ESTIMATE SELECT count() FROM logs WHERE host = 'a'",
+            Reply::error(500, 159, "Timeout exceeded").times(1),
+        )
+        .reply(
+            "EXPLAIN ESTIMATE SELECT count() FROM logs WHERE host = 'a'",
+            estimate("10"),
+        )
+        .reply(
+            "EXPLAIN ESTIMATE SELECT count() FROM logs WHERE host = 'b'",
+            Reply::error(404, 60, "Table default.logs does not exist"),
+        )
+        .reply("EXPLAIN ESTIMATE SELECT max(ts) FROM logs", estimate("5"))
+        .reply(
+            "SELECT count() FROM logs WHERE host = 'a'",
+            Reply::table(&["c"], &[&["1"]]),
+        )
+        .reply("SELECT max(ts) FROM logs", Reply::table(&["t"], &[&["1"]]));
+    let ch = server.client(Default::default(), Default::default());
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&ch));
+    let summary = warmup::execute(
+        &dashboard,
+        combinations,
+        None,
+        &ch,
+        &warmup::ExecuteFlags {
+            keep_going: true,
+            ..flags
+        },
+        &mut warmup::Budget::default().start(),
+        Default::default(),
+    )
+    .await?;
+    // The timed out estimate is retried after the other queries
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.queries, 3);
+    assert_eq!(
+        server.queries().last().unwrap(),
+        "SELECT count() FROM logs WHERE host = 'a'"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_grafana_ds_query() -> anyhow::Result<()> {
+    let server = MockGrafana::start().await;
+    server.table(
+        "SELECT count() FROM logs WHERE host = 'a'",
+        &["c"],
+        &[&["1"]],
+    );
+    let grafana = grafana::GrafanaClient::new(server.url.clone(), None, &Default::default())?;
+
+    let dashboard: grafana::Dashboard = serde_json::from_value(serde_json::json!({
+        "title": "test",
+        "panels": [{
+            "id": 1,
+            "datasource": { "type": "grafana-clickhouse-datasource", "uid": "${DS}" },
+            "targets": [{ "refId": "A", "rawSql": "SELECT count() FROM logs WHERE host = '${host}'" }]
+        }],
+        "templating": { "list": [
+            { "name": "DS", "type": "datasource", "query": "grafana-clickhouse-datasource",
+              "current": { "text": "Clickhouse", "value": "ch" } },
+            { "name": "host", "type": "custom", "query": "a",
+              "options": [{ "text": "A", "value": "a" }] },
+        ]}
+    }))?;
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&grafana));
+    let summary = warmup::execute(
+        &dashboard,
+        combinations,
+        None,
+        &grafana,
+        &warmup::ExecuteFlags {
+            backend: warmup::BackendKind::Grafana,
+            ..Default::default()
+        },
+        &mut warmup::Budget::default().start(),
+        Default::default(),
+    )
+    .await?;
+    assert_eq!(summary.queries, 1);
+
+    let requests = server.requests();
+    let body: serde_json::Value = serde_json::from_str(&requests[0].body)?;
+    // Data source variable substituted
+    assert_eq!(
+        body["queries"][0]["datasource"],
+        serde_json::json!({ "type": "grafana-clickhouse-datasource", "uid": "ch" })
+    );
+    assert_eq!(
+        body["scopedVars"],
+        serde_json::json!({
+            "DS": { "text": "Clickhouse", "value": "ch" },
+            "host": { "text": "A", "value": "a" },
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mock_grafana_keep_going() -> anyhow::Result<()> {
+    let server = MockGrafana::start().await;
+    server
+        .error(
+            "SELECT count() FROM logs",
+            400,
+            "clickhouse [execute]: code: 241, message: Memory limit exceeded",
+        )
+        .error(
+            "SELECT count() FROM missing",
+            400,
+            "clickhouse [execute]: code: 60, message: Table default.missing does not exist",
+        );
+    let grafana = grafana::GrafanaClient::new(server.url.clone(), None, &Default::default())?;
+
+    let dashboard: grafana::Dashboard = serde_json::from_value(serde_json::json!({
+        "title": "test",
+        "panels": [
+            {
+                "id": 1,
+                "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" },
+                "targets": [{ "refId": "A", "rawSql": "SELECT count() FROM logs" }]
+            },
+            {
+                "id": 2,
+                "datasource": { "type": "grafana-clickhouse-datasource", "uid": "ch" },
+                "targets": [{ "refId": "A", "rawSql": "SELECT count() FROM missing" }]
+            },
+        ],
+    }))?;
+    let selection = grafana::Selection::new(grafana::Mode::All);
+    let config = grafana::VariablesConfig::default();
+    let combinations = dashboard.variables_combinations(&config, &selection, Some(&grafana));
+    let summary = warmup::execute(
+        &dashboard,
+        combinations,
+        None,
+        &grafana,
+        &warmup::ExecuteFlags {
+            backend: warmup::BackendKind::Grafana,
+            keep_going: true,
+            ..Default::default()
+        },
+        &mut warmup::Budget::default().start(),
+        Default::default(),
+    )
+    .await?;
+    assert_eq!(summary.failed, 2);
+    // The memory limit is transient and retried once after the other queries, unlike the
+    // missing table
+    let queries: Vec<_> = server
+        .queries()
+        .iter()
+        .map(|q| q["rawSql"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        queries,
+        [
+            "SELECT count() FROM logs",
+            "SELECT count() FROM missing",
+            "SELECT count() FROM logs"
+        ]
+    );
+    Ok(())
+}